@@ -0,0 +1,490 @@
+use crate::deflate::{
+    canonical_codes, find_matches, huffman_lengths, BitWriter, Token, WINDOW_SIZE,
+};
+use std::io::{self, Write};
+
+// Input compressed per meta-block
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// Longest Huffman code, and longest code in the code length code
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 5;
+
+const LITERAL_ALPHABET: usize = 256;
+const COMMAND_ALPHABET: usize = 704;
+// 16 + NDIRECT + (48 << NPOSTFIX), with neither direct codes nor postfix bits
+const DISTANCE_ALPHABET: usize = 64;
+
+// Code length symbol repeating a zero length 3 to 10 times
+const REPEAT_ZERO: usize = 17;
+
+// Order in which the code length code lengths are sent in a complex prefix code
+const CODE_LENGTH_ORDER: [usize; 18] =
+    [1, 2, 3, 4, 0, 5, 17, 6, 16, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+// Fixed code for the code length code lengths 0..=5, as (bits, bit count)
+const CODE_LENGTH_CODE: [(u32, u32); 6] = [(0, 2), (7, 4), (3, 3), (2, 2), (1, 2), (15, 4)];
+
+// Base values and extra bits for insert length codes 0..=23
+const INSERT_BASE: [u32; 24] = [
+    0, 1, 2, 3, 4, 5, 6, 8, 10, 14, 18, 26, 34, 50, 66, 98, 130, 194, 322, 578, 1090, 2114, 6210,
+    22594,
+];
+const INSERT_EXTRA: [u8; 24] = [
+    0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 12, 14, 24,
+];
+
+// Base values and extra bits for copy length codes 0..=23
+const COPY_BASE: [u32; 24] = [
+    2, 3, 4, 5, 6, 7, 8, 9, 10, 12, 14, 18, 22, 30, 38, 54, 70, 102, 134, 198, 326, 582, 1094, 2118,
+];
+const COPY_EXTRA: [u8; 24] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 24,
+];
+
+// Compress a whole buffer at once
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = Encoder::new(Vec::new());
+    // Writing to a Vec cannot fail
+    let _ = encoder.write_all(data);
+    encoder.finish().unwrap_or_default()
+}
+
+// Streaming brotli compressor (RFC 7932)
+//
+// Reuses the LZ77 matcher of the deflate encoder and sends each block as a
+// meta-block with one literal, command and distance code, and no context
+// modeling. That compresses about as well as gzip. `flush` ends the current
+// meta-block and byte-aligns with an empty metadata block, so a reader can
+// decode everything written so far.
+pub struct Encoder<W: Write> {
+    inner: W,
+    // Up to a window of already compressed history, followed by pending input
+    window: Vec<u8>,
+    pending_start: usize,
+    bits: BitWriter,
+    started: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W) -> Self {
+        Encoder {
+            inner,
+            window: Vec::with_capacity(WINDOW_SIZE + BLOCK_SIZE),
+            pending_start: 0,
+            bits: BitWriter::default(),
+            started: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    // Compress any pending input, end the stream and return it
+    pub fn finish(mut self) -> io::Result<W> {
+        self.start();
+        self.compress_block();
+        // ISLAST and ISLASTEMPTY: an empty last meta-block
+        self.bits.write(0b11, 2);
+        self.bits.align();
+        self.write_out()?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn start(&mut self) {
+        if !self.started {
+            self.started = true;
+            // WBITS = 16, a 64KiB window, which is more than matches reach back
+            self.bits.write(0, 1);
+        }
+    }
+
+    // Compress the pending input as one meta-block and keep its tail as history
+    fn compress_block(&mut self) {
+        if self.window.len() == self.pending_start {
+            return;
+        }
+
+        let tokens = find_matches(&self.window, self.pending_start);
+        write_meta_block(&mut self.bits, &tokens, &self.window[self.pending_start..]);
+
+        if self.window.len() > WINDOW_SIZE {
+            self.window.drain(..self.window.len() - WINDOW_SIZE);
+        }
+        self.pending_start = self.window.len();
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        if !self.bits.out.is_empty() {
+            self.inner.write_all(&self.bits.out)?;
+            self.bits.out.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.start();
+
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let room = BLOCK_SIZE - (self.window.len() - self.pending_start);
+            let (now, later) = rest.split_at(room.min(rest.len()));
+            self.window.extend_from_slice(now);
+            rest = later;
+
+            if self.window.len() - self.pending_start == BLOCK_SIZE {
+                self.compress_block();
+                self.write_out()?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    // End the current meta-block and byte-align with an empty metadata block
+    fn flush(&mut self) -> io::Result<()> {
+        self.start();
+        self.compress_block();
+        // ISLAST = 0, MNIBBLES = 0 (coded as 3), reserved bit, MSKIPBYTES = 0
+        self.bits.write(0b000110, 6);
+        self.bits.align();
+        self.write_out()?;
+        self.inner.flush()
+    }
+}
+
+// Literals inserted before a copy, as in a brotli insert-and-copy command
+struct Command {
+    insert: usize,
+    // Length and distance, absent for literals that end the meta-block
+    copy: Option<(usize, usize)>,
+}
+
+// Write one meta-block, compressed or stored, whichever is smaller
+fn write_meta_block(bits: &mut BitWriter, tokens: &[Token], raw: &[u8]) {
+    let mut literals = Vec::new();
+    let mut commands = Vec::new();
+    let mut insert = 0;
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => {
+                literals.push(byte);
+                insert += 1;
+            }
+            Token::Match { length, distance } => {
+                commands.push(Command {
+                    insert,
+                    copy: Some((length, distance)),
+                });
+                insert = 0;
+            }
+        }
+    }
+    if insert > 0 {
+        commands.push(Command { insert, copy: None });
+    }
+
+    let mut compressed = BitWriter::default();
+    write_commands(&mut compressed, &commands, &literals);
+
+    // ISLAST = 0, then MNIBBLES and MLEN - 1 in as few nibbles as hold it
+    let length = raw.len() - 1;
+    let nibbles = match length {
+        length if length < 1 << 16 => 4,
+        length if length < 1 << 20 => 5,
+        _ => 6,
+    };
+    bits.write(0, 1);
+    bits.write(nibbles - 4, 2);
+    bits.write(length as u32, 4 * nibbles);
+
+    // A stored meta-block costs the ISUNCOMPRESSED bit, padding to a byte and the bytes
+    if compressed.bit_len() >= 7 + 8 * raw.len() {
+        bits.write(1, 1);
+        bits.align();
+        bits.out.extend_from_slice(raw);
+    } else {
+        bits.write(0, 1);
+        bits.append(&compressed);
+    }
+}
+
+// Write the compressed meta-block header and its commands
+fn write_commands(bits: &mut BitWriter, commands: &[Command], literals: &[u8]) {
+    let mut literal_freqs = [0u32; LITERAL_ALPHABET];
+    let mut command_freqs = [0u32; COMMAND_ALPHABET];
+    let mut distance_freqs = [0u32; DISTANCE_ALPHABET];
+    for byte in literals {
+        literal_freqs[usize::from(*byte)] += 1;
+    }
+    for command in commands {
+        command_freqs[command_code(command)] += 1;
+        if let Some((_, distance)) = command.copy {
+            distance_freqs[distance_code(distance).0] += 1;
+        }
+    }
+    let literal_lengths = huffman_lengths(&literal_freqs, MAX_CODE_LENGTH);
+    let command_lengths = huffman_lengths(&command_freqs, MAX_CODE_LENGTH);
+    let distance_lengths = huffman_lengths(&distance_freqs, MAX_CODE_LENGTH);
+
+    // One block type each for literals, commands and distances
+    bits.write(0, 3);
+    // NPOSTFIX and NDIRECT
+    bits.write(0, 2 + 4);
+    // Context mode of the literal block type, unused with a single literal code
+    bits.write(0, 2);
+    // One literal and one distance code, so no context maps
+    bits.write(0, 2);
+    write_prefix_code(bits, &literal_lengths);
+    write_prefix_code(bits, &command_lengths);
+    write_prefix_code(bits, &distance_lengths);
+
+    let literal_codes = canonical_codes(&literal_lengths);
+    let command_codes = canonical_codes(&command_lengths);
+    let distance_codes = canonical_codes(&distance_lengths);
+    let symbol = |bits: &mut BitWriter, codes: &[u16], lengths: &[u8], symbol: usize| {
+        bits.write(u32::from(codes[symbol]), u32::from(lengths[symbol]));
+    };
+
+    let mut literals = literals.iter();
+    for command in commands {
+        symbol(
+            bits,
+            &command_codes,
+            &command_lengths,
+            command_code(command),
+        );
+        let insert_code = length_code(&INSERT_BASE, command.insert);
+        bits.write(
+            command.insert as u32 - INSERT_BASE[insert_code],
+            u32::from(INSERT_EXTRA[insert_code]),
+        );
+        if let Some((length, _)) = command.copy {
+            let copy_code = length_code(&COPY_BASE, length);
+            bits.write(
+                length as u32 - COPY_BASE[copy_code],
+                u32::from(COPY_EXTRA[copy_code]),
+            );
+        }
+
+        for byte in literals.by_ref().take(command.insert) {
+            symbol(bits, &literal_codes, &literal_lengths, usize::from(*byte));
+        }
+
+        if let Some((_, distance)) = command.copy {
+            let (code, extra, extra_bits) = distance_code(distance);
+            symbol(bits, &distance_codes, &distance_lengths, code);
+            bits.write(extra, extra_bits);
+        }
+    }
+}
+
+// Write a complex prefix code with the given code lengths
+//
+// The code must be complete and use at least two symbols, as `huffman_lengths`
+// guarantees. Zero lengths are sent up to the last used symbol only, since the
+// reader stops once the code is complete.
+fn write_prefix_code(bits: &mut BitWriter, lengths: &[u8]) {
+    let end = lengths
+        .iter()
+        .rposition(|length| *length != 0)
+        .map_or(0, |last| last + 1);
+
+    // Code length symbols with their extra bits; runs of zeros become symbol 17.
+    // Consecutive 17s would multiply their counts, so a run longer than 10
+    // continues with a single literal zero in between.
+    let mut symbols = Vec::new();
+    let mut position = 0;
+    let mut repeated = false;
+    while position < end {
+        let length = lengths[position];
+        let zeros = lengths[position..end]
+            .iter()
+            .take_while(|length| **length == 0)
+            .count();
+        if zeros >= 3 && !repeated {
+            let run = zeros.min(10);
+            symbols.push((REPEAT_ZERO, run as u32 - 3));
+            position += run;
+            repeated = true;
+        } else {
+            symbols.push((usize::from(length), 0));
+            position += 1;
+            repeated = false;
+        }
+    }
+
+    let mut freqs = [0u32; 18];
+    for (symbol, _) in &symbols {
+        freqs[*symbol] += 1;
+    }
+    let code_lengths = huffman_lengths(&freqs, MAX_CODE_LENGTH_CODE_LENGTH);
+
+    // HSKIP = 0: a complex code sending every code length code length
+    bits.write(0, 2);
+    let mut space = 32;
+    for symbol in CODE_LENGTH_ORDER {
+        let length = code_lengths[symbol];
+        let (code, code_bits) = CODE_LENGTH_CODE[usize::from(length)];
+        bits.write(code, code_bits);
+        if length != 0 {
+            space -= 32 >> length;
+            if space == 0 {
+                break;
+            }
+        }
+    }
+
+    let codes = canonical_codes(&code_lengths);
+    for (symbol, extra) in symbols {
+        bits.write(u32::from(codes[symbol]), u32::from(code_lengths[symbol]));
+        if symbol == REPEAT_ZERO {
+            bits.write(extra, 3);
+        }
+    }
+}
+
+// Insert-and-copy symbol for a command, always with an explicit distance
+fn command_code(command: &Command) -> usize {
+    let insert_code = length_code(&INSERT_BASE, command.insert);
+    // The copy of a final command is never read; code 0 costs no extra bits
+    let copy_code = command
+        .copy
+        .map_or(0, |(length, _)| length_code(&COPY_BASE, length));
+    let base = match (insert_code >> 3, copy_code >> 3) {
+        (0, 0) => 128,
+        (0, 1) => 192,
+        (1, 0) => 256,
+        (1, 1) => 320,
+        (0, 2) => 384,
+        (2, 0) => 448,
+        (1, 2) => 512,
+        (2, 1) => 576,
+        _ => 640,
+    };
+    base | (insert_code & 7) << 3 | copy_code & 7
+}
+
+fn length_code(bases: &[u32; 24], length: usize) -> usize {
+    bases.partition_point(|base| *base as usize <= length) - 1
+}
+
+// Distance symbol, extra bits value and extra bit count for a backward distance
+fn distance_code(distance: usize) -> (usize, u32, u32) {
+    let offset = distance + 3;
+    let extra_bits = usize::BITS - offset.leading_zeros() - 2;
+    let code = 16 + 2 * (extra_bits as usize - 1) + ((offset >> extra_bits) & 1);
+    (code, (offset & ((1 << extra_bits) - 1)) as u32, extra_bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x9e37_79b9_u32;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            match state >> 30 {
+                0 => data.push((state >> 8) as u8),
+                1 => data.extend_from_slice(b"<li class=\"item\">"),
+                2 => data.extend_from_slice(format!("{}", state % 1000).as_bytes()),
+                _ => data.extend_from_slice(b"</li>\n"),
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn writes_empty_and_stored_streams() {
+        // WBITS = 16, then an empty last meta-block
+        assert_eq!(compress(b""), [0x06]);
+
+        // Too short to gain anything: one uncompressed meta-block of 24 bytes
+        let text = b"hello hello hello hello!";
+        let stored = compress(text);
+        assert_eq!(stored[..3], [0x70, 0x01, 0x10]);
+        assert_eq!(stored[3..stored.len() - 1], text[..]);
+        assert_eq!(stored[stored.len() - 1], 0x03);
+    }
+
+    #[test]
+    fn compresses_repetitive_input() {
+        // Checked against the reference decoder
+        assert_eq!(
+            hex(&compress(&[b'a'; 40])),
+            "700200003060dc9ee7799ee7793a03c6ed799ee7799ee7799ee7799ee7793803c6edc96303"
+        );
+
+        let data = sample(256 * 1024);
+        let compressed = compress(&data);
+        assert!(
+            compressed.len() < data.len() / 3,
+            "{} bytes",
+            compressed.len()
+        );
+    }
+
+    #[test]
+    fn flushes_mid_stream() {
+        let data = sample(100_000);
+        let mut encoder = Encoder::new(Vec::new());
+        let mut flushed = 0;
+        for piece in data.chunks(7_777) {
+            encoder.write_all(piece).unwrap();
+            encoder.flush().unwrap();
+            assert!(encoder.get_ref().len() > flushed);
+            flushed = encoder.get_ref().len();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(compressed[compressed.len() - 1], 0x03);
+        assert!(compressed.len() < data.len() / 2);
+    }
+
+    #[test]
+    fn codes_lengths_and_distances() {
+        assert_eq!(distance_code(1), (16, 0, 1));
+        assert_eq!(distance_code(2), (16, 1, 1));
+        assert_eq!(distance_code(3), (17, 0, 1));
+        assert_eq!(distance_code(5), (18, 0, 2));
+        assert_eq!(
+            distance_code(WINDOW_SIZE),
+            (42, WINDOW_SIZE as u32 + 3 - (1 << 15), 14)
+        );
+
+        assert_eq!(length_code(&INSERT_BASE, 0), 0);
+        assert_eq!(length_code(&INSERT_BASE, 7), 6);
+        assert_eq!(length_code(&INSERT_BASE, 22594 + 1000), 23);
+        assert_eq!(length_code(&COPY_BASE, 3), 1);
+        assert_eq!(length_code(&COPY_BASE, 258), 19);
+
+        let command = |insert, length| Command {
+            insert,
+            copy: Some((length, 1)),
+        };
+        assert_eq!(command_code(&command(0, 3)), 128 + 1);
+        assert_eq!(command_code(&command(10, 3)), 256 + 1);
+        assert_eq!(command_code(&command(0, 258)), 384 + 3);
+        assert_eq!(
+            command_code(&Command {
+                insert: 300,
+                copy: None
+            }),
+            448 + 8
+        );
+    }
+}
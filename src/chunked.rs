@@ -0,0 +1,116 @@
+use http::header::HeaderMap;
+use std::io::{self, Write};
+
+// Longest chunk-size line accepted, extensions included
+pub const MAX_CHUNK_LINE: usize = 4096;
+
+// Parse a chunk-size line such as `1a3f` or `1a3f;name=value`, without its CRLF
+//
+// Chunk extensions are allowed but ignored.
+pub fn parse_chunk_size(line: &[u8]) -> Option<usize> {
+    let line = std::str::from_utf8(line).ok()?;
+    let size = line.split(';').next()?.trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    usize::from_str_radix(size, 16).ok()
+}
+
+// Encodes everything written to it as `Transfer-Encoding: chunked`
+//
+// Each non-empty write becomes one chunk; call `finish` to send the last
+// chunk and any trailer fields.
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    // Terminate the body with the zero-length chunk and trailers, returning the stream
+    pub fn finish(mut self, trailers: &HeaderMap) -> io::Result<W> {
+        let mut tail = b"0\r\n".to_vec();
+        for (name, value) in trailers.iter() {
+            tail.extend_from_slice(name.as_str().as_bytes());
+            tail.extend_from_slice(b": ");
+            tail.extend_from_slice(value.as_bytes());
+            tail.extend_from_slice(b"\r\n");
+        }
+        tail.extend_from_slice(b"\r\n");
+        self.inner.write_all(&tail)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body early
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // One write per chunk keeps small chunks from turning into several packets
+        let mut chunk = format!("{:x}\r\n", buf.len()).into_bytes();
+        chunk.reserve(buf.len() + 2);
+        chunk.extend_from_slice(buf);
+        chunk.extend_from_slice(b"\r\n");
+        self.inner.write_all(&chunk)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn parses_chunk_sizes() {
+        assert_eq!(parse_chunk_size(b"0"), Some(0));
+        assert_eq!(parse_chunk_size(b"1a3F"), Some(0x1a3f));
+        assert_eq!(parse_chunk_size(b"10;name=value;flag"), Some(16));
+        assert_eq!(parse_chunk_size(b"10 \t;ext"), Some(16));
+    }
+
+    #[test]
+    fn rejects_malformed_chunk_sizes() {
+        for line in [
+            &b""[..],
+            b";ext",
+            b" 10",
+            b"+10",
+            b"-1",
+            b"0x10",
+            b"1 0",
+            b"g",
+            b"\xff",
+            b"10000000000000000",
+        ] {
+            assert_eq!(parse_chunk_size(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn writes_chunks_and_trailers() {
+        let mut writer = ChunkedWriter::new(Vec::new());
+        writer.write_all(b"hello").unwrap();
+        writer.write_all(b"").unwrap();
+        writer.write_all(&[b'x'; 26]).unwrap();
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("server-timing", HeaderValue::from_static("db;dur=53"));
+        let output = writer.finish(&trailers).unwrap();
+
+        let mut expected = b"5\r\nhello\r\n1a\r\n".to_vec();
+        expected.extend_from_slice(&[b'x'; 26]);
+        expected.extend_from_slice(b"\r\n0\r\nserver-timing: db;dur=53\r\n\r\n");
+        assert_eq!(output, expected);
+    }
+}
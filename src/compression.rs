@@ -0,0 +1,578 @@
+use crate::brotli;
+use crate::deflate::{self, Format};
+use crate::request::Request;
+use crate::response::{Body, Response};
+use http::header::{self, HeaderValue};
+use http::StatusCode;
+use log::error;
+use std::io::{self, Read, Write};
+
+// Media types whose payload is already compressed, so compressing again only costs CPU
+const ALREADY_COMPRESSED: [&str; 13] = [
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/zstd",
+    "application/pdf",
+    "application/wasm",
+    "application/octet-stream",
+    "font/woff",
+    "font/woff2",
+];
+
+// Input read from a streamed body per compression step
+const STREAM_READ_SIZE: usize = 16 * 1024;
+
+// Largest file or reader body compressed in memory, which keeps its response
+// length known; longer ones are compressed as they are sent, in chunks
+const MAX_BUFFERED_SIZE: u64 = 4 * 1024 * 1024;
+
+// Content codings the server can produce, in order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+const SUPPORTED: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Brotli => brotli::compress(data),
+            Encoding::Gzip => deflate::compress(data, Format::Gzip),
+            Encoding::Deflate => deflate::compress(data, Format::Zlib),
+        }
+    }
+
+    // Whether a coding name from Accept-Encoding refers to this encoding
+    fn matches(&self, coding: &str) -> bool {
+        match self {
+            Encoding::Brotli => coding == "br",
+            Encoding::Gzip => coding == "gzip" || coding == "x-gzip",
+            Encoding::Deflate => coding == "deflate",
+        }
+    }
+}
+
+// Response compression settings, set server-wide and overridable per route
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    pub enabled: bool,
+    // Bodies known to be smaller than this are sent as-is
+    pub min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            enabled: true,
+            min_size: 1024,
+        }
+    }
+}
+
+impl Compression {
+    pub fn disabled() -> Self {
+        Compression {
+            enabled: false,
+            ..Compression::default()
+        }
+    }
+}
+
+// Pick the preferred supported encoding the client accepts, if any
+//
+// Follows the q-values of Accept-Encoding, with `*` standing for any coding
+// not listed. Without the header, or when identity is preferred, nothing is chosen.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    let mut entries = Vec::new();
+    for item in accept_encoding?.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        if coding.is_empty() {
+            continue;
+        }
+        let quality = parts
+            .filter_map(|param| param.trim().split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+            .map_or(Some(1000), |(_, value)| parse_quality(value.trim()));
+        if let Some(quality) = quality {
+            entries.push((coding, quality));
+        }
+    }
+
+    let quality_of = |matches: &dyn Fn(&str) -> bool| {
+        entries
+            .iter()
+            .find(|(coding, _)| matches(coding))
+            .or_else(|| entries.iter().find(|(coding, _)| coding == "*"))
+            .map(|(_, quality)| *quality)
+    };
+
+    let mut best: Option<(Encoding, u16)> = None;
+    for encoding in SUPPORTED {
+        let quality = quality_of(&|coding| encoding.matches(coding)).unwrap_or(0);
+        if quality > 0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((encoding, quality));
+        }
+    }
+
+    let (encoding, quality) = best?;
+    let identity = entries
+        .iter()
+        .find(|(coding, _)| coding == "identity")
+        .map_or(0, |(_, quality)| *quality);
+    if identity > quality {
+        return None;
+    }
+    Some(encoding)
+}
+
+// Parse a q-value such as `0.8` into thousandths
+fn parse_quality(value: &str) -> Option<u16> {
+    let (whole, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 3 || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let fraction = format!("{:0<3}", fraction);
+    let quality = match whole {
+        "0" => fraction.parse().ok()?,
+        "1" if fraction == "000" => 1000,
+        _ => return None,
+    };
+    Some(quality)
+}
+
+// Whether a Content-Type is worth compressing
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    if mime == "image/svg+xml" {
+        return true;
+    }
+
+    match mime.split_once('/') {
+        Some(("image" | "audio" | "video", _)) => false,
+        Some(_) => !ALREADY_COMPRESSED.contains(&mime.as_str()),
+        None => false,
+    }
+}
+
+// Compress a handler's response for the encoding the client prefers
+//
+// Runs before `conditional::apply` so that validators describe the encoded
+// representation. Requests with a Range header get the identity encoding so
+// byte offsets refer to the unencoded content. HEAD responses are encoded
+// exactly as GET would be, so their headers match; only the body is left unsent.
+pub fn apply(request: &Request, mut response: Response, settings: Compression) -> Response {
+    let status = response.status;
+    if !settings.enabled
+        || !status.is_success()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || response.headers.contains_key(header::CONTENT_ENCODING)
+        || response
+            .body
+            .len()
+            .is_some_and(|length| length < settings.min_size)
+        || response.body.is_empty()
+    {
+        return response;
+    }
+
+    let compressible = response
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(is_compressible);
+    let no_transform = response
+        .headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+    if !compressible || no_transform {
+        return response;
+    }
+
+    // The representation now depends on Accept-Encoding, whichever one this client gets
+    let varies = response
+        .headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|name| name == "*" || name.eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+        response
+            .headers
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    if request.headers.contains_key(header::RANGE) {
+        return response;
+    }
+    let encoding = match negotiate(request.header("accept-encoding")) {
+        Some(encoding) => encoding,
+        None => return response,
+    };
+    encode(response, encoding)
+}
+
+fn encode(mut response: Response, encoding: Encoding) -> Response {
+    let bytes = match std::mem::replace(&mut response.body, Body::Empty) {
+        Body::Bytes(bytes) => bytes,
+        Body::File { file, length } if length <= MAX_BUFFERED_SIZE => {
+            match read_all(file, length) {
+                Ok(bytes) => bytes,
+                Err(err) => return read_failed(err),
+            }
+        }
+        Body::Reader { reader, length } if length <= MAX_BUFFERED_SIZE => {
+            match read_all(reader, length) {
+                Ok(bytes) => bytes,
+                Err(err) => return read_failed(err),
+            }
+        }
+        Body::File { file, length } => {
+            response.body = Body::from_stream(CompressReader::new(file.take(length), encoding));
+            return encoded_headers(response, encoding);
+        }
+        Body::Reader { reader, length } => {
+            response.body = Body::from_stream(CompressReader::new(reader.take(length), encoding));
+            return encoded_headers(response, encoding);
+        }
+        Body::Stream(reader) => {
+            response.body = Body::from_stream(CompressReader::new(reader, encoding));
+            return encoded_headers(response, encoding);
+        }
+        Body::Chunks(chunks) => {
+            response.body = Body::Chunks(Box::new(CompressChunks {
+                chunks,
+                encoder: Some(Encoder::new(encoding)),
+            }));
+            return encoded_headers(response, encoding);
+        }
+        Body::Empty => return response,
+    };
+
+    let compressed = encoding.compress(&bytes);
+    // Incompressible content is better sent as it is
+    if compressed.len() >= bytes.len() {
+        response.body = Body::Bytes(bytes);
+        return response;
+    }
+    response.body = Body::Bytes(compressed);
+    encoded_headers(response, encoding)
+}
+
+// Read a body of known length into memory
+fn read_all(reader: impl Read, length: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(length as usize);
+    reader.take(length).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "response body shorter than its declared length",
+        ));
+    }
+    Ok(bytes)
+}
+
+fn read_failed(err: io::Error) -> Response {
+    error!("Failed to read response body for compression: {}", err);
+    Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// Label a response as carrying `encoding`
+fn encoded_headers(mut response: Response, encoding: Encoding) -> Response {
+    // A strong validator must differ between encodings of the same content
+    if let Some(etag) = response
+        .headers
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|etag| etag.strip_suffix('"'))
+        .map(|etag| format!("{}-{}\"", etag, encoding.as_str()))
+    {
+        response = response.header(header::ETAG, etag);
+    }
+    response.header(header::CONTENT_ENCODING, encoding.as_str())
+}
+
+// Streaming compressor for one of the encodings, collecting its output in memory
+enum Encoder {
+    Deflate(deflate::Encoder<Vec<u8>>),
+    Brotli(brotli::Encoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Encoder::Brotli(brotli::Encoder::new(Vec::new())),
+            Encoding::Gzip => Encoder::Deflate(deflate::Encoder::new(Vec::new(), Format::Gzip)),
+            Encoding::Deflate => Encoder::Deflate(deflate::Encoder::new(Vec::new(), Format::Zlib)),
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            Encoder::Deflate(encoder) => encoder.get_mut(),
+            Encoder::Brotli(encoder) => encoder.get_mut(),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Deflate(encoder) => encoder.finish(),
+            Encoder::Brotli(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Deflate(encoder) => encoder.write(buf),
+            Encoder::Brotli(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Deflate(encoder) => encoder.flush(),
+            Encoder::Brotli(encoder) => encoder.flush(),
+        }
+    }
+}
+
+// Compresses a stream lazily as it is read
+struct CompressReader<R> {
+    source: R,
+    encoder: Option<Encoder>,
+    output: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> CompressReader<R> {
+    fn new(source: R, encoding: Encoding) -> Self {
+        CompressReader {
+            source,
+            encoder: Some(Encoder::new(encoding)),
+            output: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for CompressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.output.len() {
+            let encoder = match self.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => return Ok(0),
+            };
+
+            let mut input = [0; STREAM_READ_SIZE];
+            let read = self.source.read(&mut input)?;
+            if read == 0 {
+                if let Some(encoder) = self.encoder.take() {
+                    self.output = encoder.finish()?;
+                }
+            } else {
+                encoder.write_all(&input[..read])?;
+                self.output = std::mem::take(encoder.get_mut());
+            }
+            self.position = 0;
+        }
+
+        let available = &self.output[self.position..];
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+// Compresses each chunk and flushes it, so chunks still reach the client as they are produced
+struct CompressChunks {
+    chunks: Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>,
+    encoder: Option<Encoder>,
+}
+
+impl Iterator for CompressChunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let encoder = self.encoder.as_mut()?;
+        match self.chunks.next() {
+            Some(Ok(chunk)) => {
+                let flushed = encoder.write_all(&chunk).and_then(|_| encoder.flush());
+                Some(flushed.map(|_| std::mem::take(encoder.get_mut())))
+            }
+            Some(Err(err)) => {
+                self.encoder = None;
+                Some(Err(err))
+            }
+            None => self.encoder.take().map(Encoder::finish),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::decompress;
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        Request::for_test(method, "/page", headers, b"")
+    }
+
+    fn text() -> Vec<u8> {
+        "<p>compressible text</p>\n".repeat(200).into_bytes()
+    }
+
+    fn page(body: impl Into<Body>) -> Response {
+        Response::new(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/html")
+            .header(header::ETAG, "\"abc\"")
+            .body(body)
+    }
+
+    fn file_body(contents: &[u8]) -> Body {
+        let path = std::env::temp_dir().join(format!(
+            "compression-test-{}-{}",
+            std::process::id(),
+            contents.len()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        Body::from_file(file).unwrap()
+    }
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(negotiate(Some("gzip, deflate")), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate(Some("gzip;q=0.5, deflate")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate(Some("gzip, deflate, br")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(Some("br;q=0.5, gzip")), Some(Encoding::Gzip));
+        assert_eq!(
+            negotiate(Some("deflate;q=0.5, *;q=0.1")),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(negotiate(Some("gzip;q=0, deflate;q=0")), None);
+        assert_eq!(negotiate(Some("identity, gzip;q=0.5")), None);
+        assert_eq!(negotiate(Some("gzip;q=2")), None);
+        assert_eq!(negotiate(None), None);
+    }
+
+    #[test]
+    fn compresses_in_memory_bodies() {
+        let request = request("GET", &[("Accept-Encoding", "gzip")]);
+        let response = apply(&request, page(text()), Compression::default());
+        assert_eq!(response.headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers[header::VARY], "accept-encoding");
+        assert_eq!(response.headers[header::ETAG], "\"abc-gzip\"");
+        match response.body {
+            Body::Bytes(bytes) => {
+                assert_eq!(decompress(&bytes, Format::Gzip, 1 << 20).unwrap(), text())
+            }
+            _ => panic!("expected an in-memory body"),
+        }
+    }
+
+    #[test]
+    fn keeps_the_length_of_small_files() {
+        let request = request("GET", &[("Accept-Encoding", "deflate")]);
+        let response = apply(&request, page(file_body(&text())), Compression::default());
+        assert_eq!(response.headers[header::CONTENT_ENCODING], "deflate");
+        match response.body {
+            Body::Bytes(bytes) => {
+                assert_eq!(decompress(&bytes, Format::Zlib, 1 << 20).unwrap(), text())
+            }
+            _ => panic!("expected the file to be compressed in memory"),
+        }
+    }
+
+    #[test]
+    fn encodes_head_like_get() {
+        let head_of = |method: &str, body: Body| {
+            let request = request(method, &[("Accept-Encoding", "gzip")]);
+            let mut head = Vec::new();
+            apply(&request, page(body), Compression::default())
+                .write_head_to(&mut head)
+                .unwrap();
+            String::from_utf8(head).unwrap()
+        };
+
+        let get = head_of("GET", file_body(&text()));
+        assert!(get.contains("content-encoding: gzip"), "{}", get);
+        assert!(get.contains("etag: \"abc-gzip\""), "{}", get);
+        assert!(get.contains("Content-Length: "), "{}", get);
+        assert_eq!(head_of("HEAD", file_body(&text())), get);
+
+        // Content that does not shrink stays identity-encoded for both methods
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let get = head_of("GET", Body::from(noise.clone()));
+        assert!(!get.contains("content-encoding"), "{}", get);
+        assert_eq!(head_of("HEAD", Body::from(noise)), get);
+    }
+
+    #[test]
+    fn leaves_unsuitable_responses_alone() {
+        let gzip = [("Accept-Encoding", "gzip")];
+        let small = apply(&request("GET", &gzip), page("tiny"), Compression::default());
+        assert!(!small.headers.contains_key(header::CONTENT_ENCODING));
+
+        let image = page(text()).header(header::CONTENT_TYPE, "image/png");
+        let image = apply(&request("GET", &gzip), image, Compression::default());
+        assert!(!image.headers.contains_key(header::CONTENT_ENCODING));
+
+        let ranged = [("Accept-Encoding", "gzip"), ("Range", "bytes=0-10")];
+        let ranged = apply(
+            &request("GET", &ranged),
+            page(text()),
+            Compression::default(),
+        );
+        assert!(!ranged.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(ranged.headers[header::VARY], "accept-encoding");
+
+        let disabled = apply(
+            &request("GET", &gzip),
+            page(text()),
+            Compression::disabled(),
+        );
+        assert!(!disabled.headers.contains_key(header::CONTENT_ENCODING));
+    }
+}
@@ -0,0 +1,301 @@
+use crate::httpdate;
+use crate::request::Request;
+use crate::response::{Body, Response};
+use http::header::{self, HeaderName};
+use http::{Method, StatusCode};
+use std::time::{Duration, SystemTime};
+
+// Headers kept on a 304 so caches can update their stored response
+const NOT_MODIFIED_HEADERS: [HeaderName; 5] = [
+    header::CACHE_CONTROL,
+    header::CONTENT_LOCATION,
+    header::ETAG,
+    header::EXPIRES,
+    header::VARY,
+];
+
+// Cache-Control policy attached to a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CachePolicy {
+    // Never store the response
+    NoStore,
+    // Store, but revalidate with the server before every use
+    NoCache,
+    // Any cache may reuse the response for the given time
+    Public { max_age: Duration },
+    // Only the client's own cache may reuse the response
+    Private { max_age: Duration },
+    // Cache forever; for fingerprinted assets that never change
+    Immutable,
+    // A literal Cache-Control value
+    Custom(String),
+}
+
+impl CachePolicy {
+    pub fn header_value(&self) -> String {
+        match self {
+            CachePolicy::NoStore => "no-store".to_string(),
+            CachePolicy::NoCache => "no-cache".to_string(),
+            CachePolicy::Public { max_age } => format!("public, max-age={}", max_age.as_secs()),
+            CachePolicy::Private { max_age } => format!("private, max-age={}", max_age.as_secs()),
+            CachePolicy::Immutable => "public, max-age=31536000, immutable".to_string(),
+            CachePolicy::Custom(value) => value.clone(),
+        }
+    }
+}
+
+// Outcome of evaluating a request's preconditions against the selected representation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Passed,
+    NotModified,
+    Failed,
+}
+
+// Evaluate If-Match, If-Unmodified-Since, If-None-Match and If-Modified-Since in RFC 9110 order
+//
+// The automatic check in `apply` only covers GET and HEAD, since it runs after
+// the handler. Handlers for unsafe methods must call this themselves before
+// changing anything.
+pub fn evaluate(
+    request: &Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> Precondition {
+    let safe = request.method == Method::GET || request.method == Method::HEAD;
+
+    if let Some(if_match) = request.header("if-match") {
+        if !matches_any(if_match, etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = request
+        .header("if-unmodified-since")
+        .and_then(httpdate::parse)
+    {
+        if last_modified.is_some_and(|modified| httpdate::truncate(modified) > since) {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(if_none_match) = request.header("if-none-match") {
+        if matches_any(if_none_match, etag, false) {
+            return if safe {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if safe {
+        if let Some(since) = request
+            .header("if-modified-since")
+            .and_then(httpdate::parse)
+        {
+            if last_modified.is_some_and(|modified| httpdate::truncate(modified) <= since) {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Passed
+}
+
+// Add caching headers to a handler's response and answer conditional requests
+//
+// Successful GET/HEAD responses with an in-memory body get an ETag derived
+// from their content unless the handler set one. Preconditions are only
+// answered for GET and HEAD: for other methods the handler has already acted,
+// and a 412 would misreport a change that happened.
+pub fn apply(request: &Request, mut response: Response, policy: Option<&CachePolicy>) -> Response {
+    let cacheable = response.status.is_success() || response.status == StatusCode::NOT_MODIFIED;
+    if let Some(policy) = policy {
+        if cacheable && !response.headers.contains_key(header::CACHE_CONTROL) {
+            response = response.header(header::CACHE_CONTROL, policy.header_value());
+        }
+    }
+
+    let safe = request.method == Method::GET || request.method == Method::HEAD;
+    if response.status != StatusCode::OK || !safe {
+        return response;
+    }
+
+    if !response.headers.contains_key(header::ETAG) && policy != Some(&CachePolicy::NoStore) {
+        if let Body::Bytes(bytes) = &response.body {
+            let etag = content_etag(bytes);
+            response = response.header(header::ETAG, etag);
+        }
+    }
+
+    let etag = response
+        .headers
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let last_modified = response
+        .headers
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(httpdate::parse);
+
+    match evaluate(request, etag.as_deref(), last_modified) {
+        Precondition::Passed => response,
+        Precondition::NotModified => not_modified(response),
+        Precondition::Failed => Response::new(StatusCode::PRECONDITION_FAILED),
+    }
+}
+
+// Strong ETag built from a hash of the body
+pub fn content_etag(bytes: &[u8]) -> String {
+    format!("\"{:016x}-{:x}\"", fnv1a(bytes), bytes.len())
+}
+
+// Strong ETag for a file from its size and modification time
+pub fn file_etag(length: u64, modified: SystemTime) -> String {
+    let nanos = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", length, nanos)
+}
+
+// Turn a full response into a 304, keeping only the headers caches need
+fn not_modified(response: Response) -> Response {
+    let mut not_modified = Response::new(StatusCode::NOT_MODIFIED);
+    for name in NOT_MODIFIED_HEADERS {
+        for value in response.headers.get_all(&name) {
+            not_modified.headers.append(name.clone(), value.clone());
+        }
+    }
+    // Last-Modified is redundant next to an ETag but harmless and helps older caches
+    if let Some(value) = response.headers.get(header::LAST_MODIFIED) {
+        not_modified
+            .headers
+            .insert(header::LAST_MODIFIED, value.clone());
+    }
+    not_modified
+}
+
+// Whether a list of entity tags such as `"a", W/"b"` or `*` matches the current tag
+fn matches_any(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    // `*` matches any current representation, tagged or not
+    if list.trim() == "*" {
+        return true;
+    }
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false,
+    };
+
+    let (current_weak, current) = split_weak(etag);
+    parse_etags(list)
+        .into_iter()
+        .any(|(weak, opaque)| opaque == current && (!strong || (!weak && !current_weak)))
+}
+
+fn split_weak(etag: &str) -> (bool, &str) {
+    match etag.strip_prefix("W/") {
+        Some(opaque) => (true, opaque),
+        None => (false, etag),
+    }
+}
+
+// Parse a comma-separated list of entity tags into (weak, quoted opaque tag) pairs
+fn parse_etags(list: &str) -> Vec<(bool, &str)> {
+    let mut etags = Vec::new();
+    let mut rest = list.trim_start();
+
+    while !rest.is_empty() {
+        let (weak, tail) = split_weak(rest);
+        if !tail.starts_with('"') {
+            break;
+        }
+        let end = match tail[1..].find('"') {
+            Some(end) => end + 2,
+            None => break,
+        };
+        etags.push((weak, &tail[..end]));
+        rest = tail[end..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+
+    etags
+}
+
+// 64-bit FNV-1a hash, stable across builds unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        Request::for_test(method, "/resource", headers, b"")
+    }
+
+    fn ok(etag: &str) -> Response {
+        Response::text(StatusCode::OK, "body").header(header::ETAG, etag)
+    }
+
+    #[test]
+    fn answers_matching_if_none_match_with_304() {
+        let request = request("GET", &[("If-None-Match", "\"a\", W/\"b\"")]);
+        let response = apply(&request, ok("\"b\""), Some(&CachePolicy::NoCache));
+        assert_eq!(response.status, StatusCode::NOT_MODIFIED);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers[header::ETAG], "\"b\"");
+        assert_eq!(response.headers[header::CACHE_CONTROL], "no-cache");
+    }
+
+    #[test]
+    fn if_match_needs_a_strong_match() {
+        let strong = request("GET", &[("If-Match", "\"a\"")]);
+        assert_eq!(apply(&strong, ok("\"a\""), None).status, StatusCode::OK);
+        assert_eq!(
+            apply(&strong, ok("W/\"a\""), None).status,
+            StatusCode::PRECONDITION_FAILED
+        );
+    }
+
+    #[test]
+    fn if_modified_since_compares_whole_seconds() {
+        let modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let response = || ok("\"a\"").header(header::LAST_MODIFIED, modified);
+        let same = request("GET", &[("If-Modified-Since", modified)]);
+        assert_eq!(
+            apply(&same, response(), None).status,
+            StatusCode::NOT_MODIFIED
+        );
+        let earlier = request(
+            "GET",
+            &[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:36 GMT")],
+        );
+        assert_eq!(apply(&earlier, response(), None).status, StatusCode::OK);
+    }
+
+    #[test]
+    fn generates_etags_for_in_memory_bodies() {
+        let response = apply(
+            &request("GET", &[]),
+            Response::text(StatusCode::OK, "x"),
+            None,
+        );
+        assert_eq!(response.headers[header::ETAG], content_etag(b"x").as_str());
+    }
+
+    #[test]
+    fn leaves_unsafe_methods_to_the_handler() {
+        let request = request("POST", &[("If-Match", "\"nope\"")]);
+        let response = apply(&request, ok("\"a\""), None);
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            evaluate(&request, Some("\"a\""), None),
+            Precondition::Failed
+        );
+    }
+}
@@ -0,0 +1,511 @@
+use crate::compression::Compression;
+use crate::connection::{KeepAlive, ListenAddr};
+use crate::request::Limits;
+use crate::toml::{self, Value};
+use log::LevelFilter;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Prefix for environment variables, e.g. HTTP_SERVER_WORKERS
+const ENV_PREFIX: &str = "HTTP_SERVER_";
+
+// Help text printed for --help
+pub const USAGE: &str = "\
+Usage: rust-http-server [OPTIONS]
+
+Options:
+  --config <PATH>            TOML config file (env: HTTP_SERVER_CONFIG)
+  --listen <ADDR>            host:port or unix:/path to listen on;
+                             repeat for several
+  --workers <N>              Worker threads serving connections
+  --queue-depth <N>          Connections that may wait for a free worker
+  --idle-timeout <DURATION>  Keep-alive idle timeout, e.g. 5s or 500ms
+  --max-requests <N>         Requests served per connection before closing
+  --shutdown-timeout <DURATION>
+                             Time allowed for draining connections on shutdown
+  --max-header-size <BYTES>  Largest accepted request head
+  --header-timeout <DURATION>
+                             Time allowed for sending a request head (default 10s)
+  --max-body-size <BYTES>    Largest accepted request body
+  --max-decompressed-size <BYTES>
+                             Largest request body after undoing gzip or deflate
+  --log-level <LEVEL>        off, error, warn, info, debug or trace
+  --static-dir <PATH>        Directory served under the static prefix
+  --static-prefix <PATH>     URL prefix for static files (default /static)
+  --directory-listing <BOOL> List directories that have no index.html
+  --static-max-age <DURATION>
+                             Cache-Control max-age for static files (default 1h)
+  --compression <BOOL>       Compress responses with brotli, gzip or deflate
+                             (default true)
+  --compression-min-size <BYTES>
+                             Smallest body worth compressing (default 1024)
+  -h, --help                 Print this help
+
+Every option can also be set in the config file under the same name with
+underscores (max_body_size = 1048576) or in the environment with the
+HTTP_SERVER_ prefix (HTTP_SERVER_MAX_BODY_SIZE=1048576). Flags override
+environment variables, which override the config file.
+";
+
+// Errors raised while assembling or validating the configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    // --help was given; not a failure, but startup should stop
+    HelpRequested,
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::ParseError),
+    UnknownKey(String),
+    MissingValue(String),
+    InvalidValue { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::HelpRequested => write!(f, "help requested"),
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::UnknownKey(key) => write!(f, "unknown setting {}", key),
+            ConfigError::MissingValue(flag) => write!(f, "missing value for {}", flag),
+            ConfigError::InvalidValue { key, message } => {
+                write!(f, "invalid value for {}: {}", key, message)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+// Server settings, layered from defaults, a config file, the environment and CLI flags
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<ListenAddr>,
+    pub workers: usize,
+    pub queue_depth: usize,
+    pub idle_timeout: Duration,
+    pub max_requests: usize,
+    pub shutdown_timeout: Duration,
+    pub max_header_size: usize,
+    pub header_timeout: Duration,
+    pub max_body_size: usize,
+    pub max_decompressed_size: usize,
+    pub log_level: LevelFilter,
+    pub static_dir: Option<PathBuf>,
+    pub static_prefix: String,
+    pub directory_listing: bool,
+    pub static_max_age: Duration,
+    pub compression: bool,
+    pub compression_min_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let limits = Limits::default();
+        let keep_alive = KeepAlive::default();
+        let compression = Compression::default();
+
+        Config {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 8080)))],
+            workers: 16,
+            queue_depth: 64,
+            idle_timeout: keep_alive.idle_timeout,
+            max_requests: keep_alive.max_requests,
+            shutdown_timeout: Duration::from_secs(10),
+            max_header_size: limits.max_header_size,
+            header_timeout: limits.header_timeout,
+            max_body_size: limits.max_body_size,
+            max_decompressed_size: limits.max_decompressed_size,
+            log_level: LevelFilter::Info,
+            static_dir: None,
+            static_prefix: "/static".to_string(),
+            directory_listing: false,
+            static_max_age: Duration::from_secs(3600),
+            compression: compression.enabled,
+            compression_min_size: compression.min_size as usize,
+        }
+    }
+}
+
+impl Config {
+    // Load the configuration for this process from its arguments and environment
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Config::load(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    // Layer defaults, config file, environment variables and flags, then validate
+    pub fn load<I, E>(args: I, env: E) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+        E: Fn(&str) -> Option<String>,
+    {
+        let flags = parse_flags(args)?;
+        let mut config = Config::default();
+
+        let config_path = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| env(&format!("{}CONFIG", ENV_PREFIX)));
+        if let Some(path) = config_path {
+            config.apply_file(Path::new(&path))?;
+        }
+
+        for key in KEYS {
+            if let Some(value) = env(&format!("{}{}", ENV_PREFIX, key.to_ascii_uppercase())) {
+                config.apply_str(key, &value)?;
+            }
+        }
+
+        // Repeated --listen flags accumulate rather than override each other
+        let mut listen_flags = Vec::new();
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            if key == "listen" {
+                listen_flags.extend(parse_addresses(key, value)?);
+            } else {
+                config.apply_str(key, value)?;
+            }
+        }
+        if !listen_flags.is_empty() {
+            config.listen = listen_flags;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_header_size: self.max_header_size,
+            max_body_size: self.max_body_size,
+            max_decompressed_size: self.max_decompressed_size,
+            header_timeout: self.header_timeout,
+        }
+    }
+
+    pub fn keep_alive(&self) -> KeepAlive {
+        KeepAlive {
+            idle_timeout: self.idle_timeout,
+            max_requests: self.max_requests,
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        Compression {
+            enabled: self.compression,
+            min_size: self.compression_min_size as u64,
+        }
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        let entries =
+            toml::parse(&contents).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+
+        for (key, value) in entries {
+            self.apply(&key, &value)?;
+        }
+        Ok(())
+    }
+
+    // Apply a setting given as text, as it arrives from flags and environment variables
+    fn apply_str(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        self.apply(key, &Value::String(value.to_string()))
+    }
+
+    fn apply(&mut self, key: &str, value: &Value) -> Result<(), ConfigError> {
+        match key {
+            "listen" => {
+                self.listen = match value {
+                    Value::Array(values) => {
+                        let mut addresses = Vec::new();
+                        for value in values {
+                            addresses.extend(parse_addresses(key, &as_string(key, value)?)?);
+                        }
+                        addresses
+                    }
+                    value => parse_addresses(key, &as_string(key, value)?)?,
+                }
+            }
+            "workers" => self.workers = as_usize(key, value)?,
+            "queue_depth" => self.queue_depth = as_usize(key, value)?,
+            "idle_timeout" => self.idle_timeout = as_duration(key, value)?,
+            "max_requests" => self.max_requests = as_usize(key, value)?,
+            "shutdown_timeout" => self.shutdown_timeout = as_duration(key, value)?,
+            "max_header_size" => self.max_header_size = as_usize(key, value)?,
+            "header_timeout" => self.header_timeout = as_duration(key, value)?,
+            "max_body_size" => self.max_body_size = as_usize(key, value)?,
+            "max_decompressed_size" => self.max_decompressed_size = as_usize(key, value)?,
+            "log_level" => {
+                self.log_level = as_string(key, value)?
+                    .parse()
+                    .map_err(|_| invalid(key, "expected off, error, warn, info, debug or trace"))?
+            }
+            "static_dir" => self.static_dir = Some(PathBuf::from(as_string(key, value)?)),
+            "static_prefix" => {
+                self.static_prefix = as_string(key, value)?.trim_end_matches('/').to_string()
+            }
+            "directory_listing" => self.directory_listing = as_bool(key, value)?,
+            "static_max_age" => self.static_max_age = as_duration(key, value)?,
+            "compression" => self.compression = as_bool(key, value)?,
+            "compression_min_size" => self.compression_min_size = as_usize(key, value)?,
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let positive = [
+            ("workers", self.workers),
+            ("queue_depth", self.queue_depth),
+            ("max_requests", self.max_requests),
+            ("max_header_size", self.max_header_size),
+        ];
+        for (key, value) in positive {
+            if value == 0 {
+                return Err(invalid(key, "must be greater than zero"));
+            }
+        }
+
+        if self.listen.is_empty() {
+            return Err(invalid("listen", "at least one address is required"));
+        }
+        if self.idle_timeout.is_zero() {
+            return Err(invalid("idle_timeout", "must be greater than zero"));
+        }
+        if self.header_timeout.is_zero() {
+            return Err(invalid("header_timeout", "must be greater than zero"));
+        }
+
+        if !self.static_prefix.starts_with('/')
+            || self.static_prefix.contains([':', '*'])
+            || self.static_prefix.len() < 2
+        {
+            return Err(invalid(
+                "static_prefix",
+                "must be a path such as /static without : or * segments",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Settings that can be given in every layer
+const KEYS: [&str; 17] = [
+    "listen",
+    "workers",
+    "queue_depth",
+    "idle_timeout",
+    "max_requests",
+    "shutdown_timeout",
+    "max_header_size",
+    "header_timeout",
+    "max_body_size",
+    "max_decompressed_size",
+    "log_level",
+    "static_dir",
+    "static_prefix",
+    "directory_listing",
+    "static_max_age",
+    "compression",
+    "compression_min_size",
+];
+
+// Collect `--name value` and `--name=value` flags as `(name, value)` with dashes turned into underscores
+fn parse_flags<I>(args: I) -> Result<Vec<(String, String)>, ConfigError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::HelpRequested);
+        }
+
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| ConfigError::UnknownKey(arg.clone()))?;
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(arg.clone()))?;
+                (flag.to_string(), value)
+            }
+        };
+
+        let key = name.replace('-', "_");
+        if key != "config" && !KEYS.contains(&key.as_str()) {
+            return Err(ConfigError::UnknownKey(arg));
+        }
+        flags.push((key, value));
+    }
+
+    Ok(flags)
+}
+
+fn invalid(key: &str, message: &str) -> ConfigError {
+    ConfigError::InvalidValue {
+        key: key.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn as_string(key: &str, value: &Value) -> Result<String, ConfigError> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        other => Err(invalid(key, &format!("expected a string, got {}", other))),
+    }
+}
+
+fn as_bool(key: &str, value: &Value) -> Result<bool, ConfigError> {
+    match value {
+        Value::Boolean(value) => Ok(*value),
+        Value::String(value) => match value.trim() {
+            "true" | "1" | "yes" | "on" => Ok(true),
+            "false" | "0" | "no" | "off" => Ok(false),
+            _ => Err(invalid(
+                key,
+                &format!("expected true or false, got {:?}", value),
+            )),
+        },
+        other => Err(invalid(
+            key,
+            &format!("expected true or false, got {}", other),
+        )),
+    }
+}
+
+fn as_usize(key: &str, value: &Value) -> Result<usize, ConfigError> {
+    match value {
+        Value::Integer(value) => {
+            usize::try_from(*value).map_err(|_| invalid(key, "must not be negative"))
+        }
+        Value::String(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| invalid(key, &format!("expected a number, got {:?}", value))),
+        other => Err(invalid(key, &format!("expected a number, got {}", other))),
+    }
+}
+
+// Durations are whole seconds, or strings with an `ms`, `s` or `m` suffix
+fn as_duration(key: &str, value: &Value) -> Result<Duration, ConfigError> {
+    let text = match value {
+        Value::Integer(_) => {
+            return as_usize(key, value).map(|secs| Duration::from_secs(secs as u64))
+        }
+        Value::String(text) => text.trim(),
+        other => return Err(invalid(key, &format!("expected a duration, got {}", other))),
+    };
+
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| invalid(key, &format!("expected a duration, got {:?}", text)))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => number
+            .checked_mul(60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| invalid(key, &format!("duration {:?} is too long", text))),
+        _ => Err(invalid(key, &format!("unknown duration unit {:?}", unit))),
+    }
+}
+
+// Resolve a comma-separated list of `host:port` and `unix:/path` addresses
+fn parse_addresses(key: &str, value: &str) -> Result<Vec<ListenAddr>, ConfigError> {
+    let mut addresses = Vec::new();
+    for address in value.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                if path.is_empty() {
+                    return Err(invalid(key, "unix socket path must not be empty"));
+                }
+                addresses.push(ListenAddr::Unix(PathBuf::from(path)));
+                continue;
+            }
+            #[cfg(not(unix))]
+            return Err(invalid(
+                key,
+                &format!("{}: unix sockets are not supported", path),
+            ));
+        }
+
+        let resolved = address
+            .to_socket_addrs()
+            .map_err(|err| invalid(key, &format!("{}: {}", address, err)))?;
+        addresses.extend(resolved.take(1).map(ListenAddr::Tcp));
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::load(args.iter().map(|arg| arg.to_string()), |_| None)
+    }
+
+    fn invalid_key(result: Result<Config, ConfigError>) -> String {
+        match result {
+            Err(ConfigError::InvalidValue { key, .. }) => key,
+            other => panic!("expected an invalid value, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn parses_durations_with_units() {
+        let config = load(&["--idle-timeout", "1500ms", "--shutdown-timeout", "2m"]).unwrap();
+        assert_eq!(config.idle_timeout, Duration::from_millis(1500));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(120));
+        assert_eq!(
+            load(&["--idle-timeout", "7"]).unwrap().idle_timeout,
+            Duration::from_secs(7)
+        );
+    }
+
+    #[test]
+    fn rejects_durations_that_overflow() {
+        let minutes = format!("{}m", u64::MAX / 60 + 1);
+        assert_eq!(
+            invalid_key(load(&["--shutdown-timeout", &minutes])),
+            "shutdown_timeout"
+        );
+        assert_eq!(
+            invalid_key(load(&["--idle-timeout", "99999999999999999999s"])),
+            "idle_timeout"
+        );
+        assert_eq!(invalid_key(load(&["--idle-timeout", "5h"])), "idle_timeout");
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let config = Config::load(
+            ["--workers".to_string(), "3".to_string()],
+            |name| match name {
+                "HTTP_SERVER_WORKERS" => Some("9".to_string()),
+                "HTTP_SERVER_MAX_REQUESTS" => Some("5".to_string()),
+                _ => None,
+            },
+        )
+        .unwrap();
+        assert_eq!(config.workers, 3);
+        assert_eq!(config.max_requests, 5);
+    }
+}
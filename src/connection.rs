@@ -0,0 +1,278 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::fs::FileTypeExt,
+    os::unix::io::{AsRawFd, RawFd},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+};
+
+// Settings for persistent (keep-alive) connections
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    // How long to wait for the next request before closing an idle connection
+    pub idle_timeout: Duration,
+    // Number of requests served on one connection before it is closed
+    pub max_requests: usize,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        KeepAlive {
+            idle_timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+// An address the server can listen on: TCP (IPv4 or IPv6) or a Unix domain socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// A bound listening socket
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    // Bind to an address, replacing a stale Unix socket file left behind by a previous run
+    pub fn bind(address: &ListenAddr) -> io::Result<Self> {
+        match address {
+            ListenAddr::Tcp(address) => TcpListener::bind(address).map(Listener::Tcp),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                if let Ok(metadata) = fs::symlink_metadata(path) {
+                    if metadata.file_type().is_socket() && UnixStream::connect(path).is_err() {
+                        fs::remove_file(path)?;
+                    }
+                }
+                UnixListener::bind(path).map(|listener| Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    // The address actually bound, e.g. with the port chosen for `:0` filled in
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    // Accept a connection, returned in blocking mode whatever the listener's mode
+    pub fn accept(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Listener::Tcp(listener) => Stream::Tcp(listener.accept()?.0),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Stream::Unix(listener.accept()?.0),
+        };
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// A connected client socket
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send a line from the client and read it back on the accepted stream
+    fn exchange(client: &mut impl Write, listener: &Listener) {
+        client.write_all(b"ping").unwrap();
+        let mut accepted = listener.accept().unwrap();
+        let mut received = [0; 4];
+        accepted.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[test]
+    fn binds_tcp_addresses() {
+        let listener = Listener::bind(&ListenAddr::Tcp("127.0.0.1:0".parse().unwrap())).unwrap();
+        let address = match listener.local_addr().unwrap() {
+            ListenAddr::Tcp(address) => address,
+            #[cfg(unix)]
+            other => panic!("unexpected address {}", other),
+        };
+        assert_ne!(address.port(), 0);
+        exchange(&mut TcpStream::connect(address).unwrap(), &listener);
+    }
+
+    #[cfg(unix)]
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "connection-test-{}-{}.sock",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binds_unix_sockets_and_removes_them_on_drop() {
+        let path = socket_path("bind");
+        let address = ListenAddr::Unix(path.clone());
+        let listener = Listener::bind(&address).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), address);
+        assert_eq!(address.to_string(), format!("unix:{}", path.display()));
+        exchange(&mut UnixStream::connect(&path).unwrap(), &listener);
+
+        drop(listener);
+        assert!(fs::symlink_metadata(&path).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaces_only_stale_unix_sockets() {
+        // A socket file nobody listens on any more, as left by a crashed run
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(fs::symlink_metadata(&path).is_ok());
+        let listener = Listener::bind(&ListenAddr::Unix(path.clone())).unwrap();
+        exchange(&mut UnixStream::connect(&path).unwrap(), &listener);
+
+        // A live socket belongs to another server and is left alone
+        let err = Listener::bind(&ListenAddr::Unix(path.clone()))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        // The probe that found it alive reaches the live server as an empty connection
+        let mut probe = listener.accept().unwrap();
+        assert_eq!(probe.read(&mut [0; 1]).unwrap(), 0);
+        exchange(&mut UnixStream::connect(&path).unwrap(), &listener);
+        drop(listener);
+
+        // So is anything that is not a socket
+        let path = socket_path("file");
+        fs::write(&path, "data").unwrap();
+        assert!(Listener::bind(&ListenAddr::Unix(path.clone())).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_file(&path).unwrap();
+    }
+}
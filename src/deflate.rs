@@ -0,0 +1,890 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
+
+// Input compressed per block; stored blocks reuse the same split, so it must stay below 64KiB
+const BLOCK_SIZE: usize = 32 * 1024;
+// How far back a match may reach
+pub(crate) const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// Three-byte matches further back than this usually cost more than the literals
+const MAX_SHORT_MATCH_DISTANCE: usize = 4096;
+// Hash chain entries examined per match search
+const MAX_CHAIN: usize = 128;
+// A match this long is taken without looking for a better one
+const NICE_MATCH: usize = 128;
+const HASH_BITS: u32 = 15;
+
+// Longest Huffman code for literals/lengths and distances, and for the code length alphabet
+const MAX_CODE_LENGTH: u8 = 15;
+const MAX_CODE_LENGTH_CODE_LENGTH: u8 = 7;
+
+pub(crate) const END_OF_BLOCK: usize = 256;
+
+// Base values and extra bits for length codes 257..=285
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Base values and extra bits for distance codes 0..=29
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// Order in which the code length code lengths are sent in a dynamic block header
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+// Deflate with a 32KiB window at the default level; the pair is a multiple of 31 as required
+const ZLIB_HEADER: [u8; 2] = [0x78, 0x9c];
+
+const CRC_TABLE: [u32; 256] = crc_table();
+pub(crate) const CRC32_INIT: u32 = 0xffff_ffff;
+pub(crate) const ADLER32_INIT: (u32, u32) = (1, 0);
+
+// Container around the raw deflate stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // RFC 1950, used by the `deflate` content coding
+    Zlib,
+    // RFC 1952, used by the `gzip` content coding
+    Gzip,
+}
+
+// Compress a whole buffer at once
+pub fn compress(data: &[u8], format: Format) -> Vec<u8> {
+    let mut encoder = Encoder::new(Vec::new(), format);
+    // Writing to a Vec cannot fail
+    let _ = encoder.write_all(data);
+    encoder.finish().unwrap_or_default()
+}
+
+// Streaming deflate compressor
+//
+// Input is compressed a block at a time as it accumulates. `flush` emits
+// everything written so far (a sync flush) so a reader can decode it before
+// the stream ends; `finish` ends the stream and writes the checksum trailer.
+pub struct Encoder<W: Write> {
+    inner: W,
+    format: Format,
+    // Up to a window of already compressed history, followed by pending input
+    window: Vec<u8>,
+    pending_start: usize,
+    bits: BitWriter,
+    crc: u32,
+    adler: (u32, u32),
+    size: u32,
+    started: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W, format: Format) -> Self {
+        Encoder {
+            inner,
+            format,
+            window: Vec::with_capacity(WINDOW_SIZE + BLOCK_SIZE),
+            pending_start: 0,
+            bits: BitWriter::default(),
+            crc: CRC32_INIT,
+            adler: ADLER32_INIT,
+            size: 0,
+            started: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    // Compress any pending input as the last block, write the trailer and return the stream
+    pub fn finish(mut self) -> io::Result<W> {
+        self.start();
+        self.compress_block(true);
+        self.bits.align();
+
+        match self.format {
+            Format::Zlib => {
+                let adler = adler32_finish(self.adler);
+                self.bits.out.extend_from_slice(&adler.to_be_bytes());
+            }
+            Format::Gzip => {
+                self.bits.out.extend_from_slice(&(!self.crc).to_le_bytes());
+                self.bits.out.extend_from_slice(&self.size.to_le_bytes());
+            }
+        }
+
+        self.write_out()?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn start(&mut self) {
+        if !self.started {
+            self.started = true;
+            match self.format {
+                Format::Zlib => self.bits.out.extend_from_slice(&ZLIB_HEADER),
+                Format::Gzip => self.bits.out.extend_from_slice(&GZIP_HEADER),
+            }
+        }
+    }
+
+    fn update_checksums(&mut self, data: &[u8]) {
+        match self.format {
+            Format::Zlib => self.adler = adler32_update(self.adler, data),
+            Format::Gzip => {
+                self.crc = crc32_update(self.crc, data);
+                self.size = self.size.wrapping_add(data.len() as u32);
+            }
+        }
+    }
+
+    // Compress the pending input as one block and keep its tail as history
+    fn compress_block(&mut self, last: bool) {
+        if self.window.len() == self.pending_start && !last {
+            return;
+        }
+
+        let tokens = find_matches(&self.window, self.pending_start);
+        write_block(
+            &mut self.bits,
+            &tokens,
+            &self.window[self.pending_start..],
+            last,
+        );
+
+        if self.window.len() > WINDOW_SIZE {
+            self.window.drain(..self.window.len() - WINDOW_SIZE);
+        }
+        self.pending_start = self.window.len();
+    }
+
+    fn write_out(&mut self) -> io::Result<()> {
+        if !self.bits.out.is_empty() {
+            self.inner.write_all(&self.bits.out)?;
+            self.bits.out.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.start();
+        self.update_checksums(buf);
+
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let room = BLOCK_SIZE - (self.window.len() - self.pending_start);
+            let (now, later) = rest.split_at(room.min(rest.len()));
+            self.window.extend_from_slice(now);
+            rest = later;
+
+            if self.window.len() - self.pending_start == BLOCK_SIZE {
+                self.compress_block(false);
+                self.write_out()?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    // Sync flush: end the current block and byte-align with an empty stored block
+    fn flush(&mut self) -> io::Result<()> {
+        self.start();
+        self.compress_block(false);
+        self.bits.write(0, 3);
+        self.bits.align();
+        self.bits.out.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        self.write_out()?;
+        self.inner.flush()
+    }
+}
+
+// Collects bits least significant first, as deflate and brotli pack them
+#[derive(Default)]
+pub(crate) struct BitWriter {
+    pub(crate) out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    pub(crate) fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= u64::from(value) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    pub(crate) fn align(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+            self.buffer = 0;
+            self.count = 0;
+        }
+    }
+
+    // Bits written so far, including those not yet making up a byte
+    pub(crate) fn bit_len(&self) -> usize {
+        self.out.len() * 8 + self.count as usize
+    }
+
+    // Write everything another writer has collected
+    pub(crate) fn append(&mut self, other: &BitWriter) {
+        for byte in &other.out {
+            self.write(u32::from(*byte), 8);
+        }
+        self.write(other.buffer as u32, other.count);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Token {
+    Literal(u8),
+    Match { length: usize, distance: usize },
+}
+
+// LZ77 over `data[start..]`, allowing matches back into the history before `start`
+pub(crate) fn find_matches(data: &[u8], start: usize) -> Vec<Token> {
+    let mut head = vec![u32::MAX; 1 << HASH_BITS];
+    let mut prev = vec![u32::MAX; data.len()];
+    for pos in 0..start {
+        insert(data, &mut head, &mut prev, pos);
+    }
+
+    let mut tokens = Vec::with_capacity((data.len() - start) / 2);
+    let mut pos = start;
+    // Match already found at `pos` while deciding on the previous position
+    let mut lookahead: Option<(usize, usize)> = None;
+
+    while pos < data.len() {
+        let (length, distance) = lookahead
+            .take()
+            .unwrap_or_else(|| longest_match(data, pos, &head, &prev));
+        insert(data, &mut head, &mut prev, pos);
+
+        // Lazy matching: emit a literal instead if the next position matches longer
+        if (MIN_MATCH..NICE_MATCH).contains(&length) && pos + 1 < data.len() {
+            let next = longest_match(data, pos + 1, &head, &prev);
+            if next.0 > length {
+                tokens.push(Token::Literal(data[pos]));
+                lookahead = Some(next);
+                pos += 1;
+                continue;
+            }
+        }
+
+        if length >= MIN_MATCH {
+            tokens.push(Token::Match { length, distance });
+            for skipped in pos + 1..pos + length {
+                insert(data, &mut head, &mut prev, skipped);
+            }
+            pos += length;
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+        }
+    }
+
+    tokens
+}
+
+// Add a position to the hash chains
+fn insert(data: &[u8], head: &mut [u32], prev: &mut [u32], pos: usize) {
+    if pos + MIN_MATCH <= data.len() {
+        let hash = hash(data, pos);
+        prev[pos] = head[hash];
+        head[hash] = pos as u32;
+    }
+}
+
+fn hash(data: &[u8], pos: usize) -> usize {
+    let value = u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], 0]);
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+// Longest earlier occurrence of the bytes at `pos`, as (length, distance)
+fn longest_match(data: &[u8], pos: usize, head: &[u32], prev: &[u32]) -> (usize, usize) {
+    if pos + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+
+    let max_length = MAX_MATCH.min(data.len() - pos);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(data, pos)];
+    let mut chain = MAX_CHAIN;
+
+    while candidate != u32::MAX && chain > 0 {
+        let earlier = candidate as usize;
+        let distance = pos - earlier;
+        if distance > WINDOW_SIZE {
+            break;
+        }
+
+        // Only a candidate that also matches one byte further can beat the current best
+        if data[earlier + best.0] == data[pos + best.0] {
+            let length = data[earlier..earlier + max_length]
+                .iter()
+                .zip(&data[pos..pos + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 && (length > MIN_MATCH || distance <= MAX_SHORT_MATCH_DISTANCE) {
+                best = (length, distance);
+                if length >= max_length || length >= NICE_MATCH {
+                    break;
+                }
+            }
+        }
+
+        candidate = prev[earlier];
+        chain -= 1;
+    }
+
+    if best.0 < MIN_MATCH {
+        (0, 0)
+    } else {
+        best
+    }
+}
+
+pub(crate) fn length_code(length: usize) -> usize {
+    LENGTH_BASE.partition_point(|base| usize::from(*base) <= length) - 1
+}
+
+pub(crate) fn distance_code(distance: usize) -> usize {
+    DISTANCE_BASE.partition_point(|base| usize::from(*base) <= distance) - 1
+}
+
+// Code lengths of the fixed Huffman codes
+pub(crate) fn fixed_lengths() -> ([u8; 288], [u8; 30]) {
+    let mut literals = [8; 288];
+    literals[144..256].fill(9);
+    literals[256..280].fill(7);
+    (literals, [5; 30])
+}
+
+// Write one block as stored, fixed or dynamic Huffman, whichever is smallest
+fn write_block(bits: &mut BitWriter, tokens: &[Token], raw: &[u8], last: bool) {
+    let mut literal_freqs = [0u32; 286];
+    let mut distance_freqs = [0u32; 30];
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => literal_freqs[usize::from(byte)] += 1,
+            Token::Match { length, distance } => {
+                literal_freqs[257 + length_code(length)] += 1;
+                distance_freqs[distance_code(distance)] += 1;
+            }
+        }
+    }
+    literal_freqs[END_OF_BLOCK] = 1;
+
+    let literal_lengths = huffman_lengths(&literal_freqs, MAX_CODE_LENGTH);
+    let distance_lengths = huffman_lengths(&distance_freqs, MAX_CODE_LENGTH);
+    let header = DynamicHeader::new(&literal_lengths, &distance_lengths);
+    let (fixed_literals, fixed_distances) = fixed_lengths();
+
+    let dynamic_cost = header.cost() + data_cost(tokens, &literal_lengths, &distance_lengths);
+    let fixed_cost = data_cost(tokens, &fixed_literals, &fixed_distances);
+    // Block header, worst-case alignment, LEN and NLEN, then the bytes themselves
+    let stored_cost = 3 + 7 + 32 + 8 * raw.len() as u64;
+
+    bits.write(u32::from(last), 1);
+    if stored_cost <= dynamic_cost.min(fixed_cost) {
+        bits.write(0, 2);
+        bits.align();
+        let length = raw.len() as u16;
+        bits.out.extend_from_slice(&length.to_le_bytes());
+        bits.out.extend_from_slice(&(!length).to_le_bytes());
+        bits.out.extend_from_slice(raw);
+    } else if fixed_cost <= dynamic_cost {
+        bits.write(1, 2);
+        write_tokens(bits, tokens, &fixed_literals, &fixed_distances);
+    } else {
+        bits.write(2, 2);
+        header.write(bits);
+        write_tokens(bits, tokens, &literal_lengths, &distance_lengths);
+    }
+}
+
+// Bits needed for the tokens and end-of-block code with the given code lengths
+fn data_cost(tokens: &[Token], literal_lengths: &[u8], distance_lengths: &[u8]) -> u64 {
+    let mut cost = u64::from(literal_lengths[END_OF_BLOCK]);
+    for token in tokens {
+        cost += match *token {
+            Token::Literal(byte) => u64::from(literal_lengths[usize::from(byte)]),
+            Token::Match { length, distance } => {
+                let length_code = length_code(length);
+                let distance_code = distance_code(distance);
+                u64::from(literal_lengths[257 + length_code])
+                    + u64::from(LENGTH_EXTRA[length_code])
+                    + u64::from(distance_lengths[distance_code])
+                    + u64::from(DISTANCE_EXTRA[distance_code])
+            }
+        };
+    }
+    cost
+}
+
+fn write_tokens(
+    bits: &mut BitWriter,
+    tokens: &[Token],
+    literal_lengths: &[u8],
+    distance_lengths: &[u8],
+) {
+    let literal_codes = canonical_codes(literal_lengths);
+    let distance_codes = canonical_codes(distance_lengths);
+    let symbol = |bits: &mut BitWriter, codes: &[u16], lengths: &[u8], symbol: usize| {
+        bits.write(u32::from(codes[symbol]), u32::from(lengths[symbol]));
+    };
+
+    for token in tokens {
+        match *token {
+            Token::Literal(byte) => {
+                symbol(bits, &literal_codes, literal_lengths, usize::from(byte));
+            }
+            Token::Match { length, distance } => {
+                let code = length_code(length);
+                symbol(bits, &literal_codes, literal_lengths, 257 + code);
+                bits.write(
+                    (length - usize::from(LENGTH_BASE[code])) as u32,
+                    u32::from(LENGTH_EXTRA[code]),
+                );
+
+                let code = distance_code(distance);
+                symbol(bits, &distance_codes, distance_lengths, code);
+                bits.write(
+                    (distance - usize::from(DISTANCE_BASE[code])) as u32,
+                    u32::from(DISTANCE_EXTRA[code]),
+                );
+            }
+        }
+    }
+    symbol(bits, &literal_codes, literal_lengths, END_OF_BLOCK);
+}
+
+// The code length sequences of a dynamic block, run-length encoded with symbols 16-18
+struct DynamicHeader {
+    literal_count: usize,
+    distance_count: usize,
+    code_length_count: usize,
+    code_lengths: Vec<u8>,
+    // (symbol, extra bits value)
+    runs: Vec<(usize, u32)>,
+}
+
+impl DynamicHeader {
+    fn new(literal_lengths: &[u8], distance_lengths: &[u8]) -> Self {
+        let used = |lengths: &[u8], minimum: usize| {
+            lengths
+                .iter()
+                .rposition(|length| *length != 0)
+                .map_or(0, |last| last + 1)
+                .max(minimum)
+        };
+        let literal_count = used(literal_lengths, 257);
+        let distance_count = used(distance_lengths, 1);
+
+        let lengths: Vec<u8> = literal_lengths[..literal_count]
+            .iter()
+            .chain(&distance_lengths[..distance_count])
+            .copied()
+            .collect();
+
+        let mut runs = Vec::new();
+        let mut index = 0;
+        while index < lengths.len() {
+            let length = lengths[index];
+            let run = lengths[index..]
+                .iter()
+                .take_while(|other| **other == length)
+                .count();
+
+            if length == 0 {
+                let mut remaining = run;
+                while remaining > 0 {
+                    if remaining >= 11 {
+                        let count = remaining.min(138);
+                        runs.push((18, (count - 11) as u32));
+                        remaining -= count;
+                    } else if remaining >= 3 {
+                        runs.push((17, (remaining - 3) as u32));
+                        remaining = 0;
+                    } else {
+                        runs.push((0, 0));
+                        remaining -= 1;
+                    }
+                }
+            } else {
+                runs.push((usize::from(length), 0));
+                let mut remaining = run - 1;
+                while remaining >= 3 {
+                    let count = remaining.min(6);
+                    runs.push((16, (count - 3) as u32));
+                    remaining -= count;
+                }
+                runs.extend(std::iter::repeat_n((usize::from(length), 0), remaining));
+            }
+            index += run;
+        }
+
+        let mut freqs = [0u32; 19];
+        for (symbol, _) in &runs {
+            freqs[*symbol] += 1;
+        }
+        let code_lengths = huffman_lengths(&freqs, MAX_CODE_LENGTH_CODE_LENGTH);
+        let code_length_count = CODE_LENGTH_ORDER
+            .iter()
+            .rposition(|symbol| code_lengths[*symbol] != 0)
+            .map_or(0, |last| last + 1)
+            .max(4);
+
+        DynamicHeader {
+            literal_count,
+            distance_count,
+            code_length_count,
+            code_lengths,
+            runs,
+        }
+    }
+
+    fn cost(&self) -> u64 {
+        let runs: u64 = self
+            .runs
+            .iter()
+            .map(|(symbol, _)| {
+                u64::from(self.code_lengths[*symbol]) + run_extra_bits(*symbol) as u64
+            })
+            .sum();
+        5 + 5 + 4 + 3 * self.code_length_count as u64 + runs
+    }
+
+    fn write(&self, bits: &mut BitWriter) {
+        bits.write((self.literal_count - 257) as u32, 5);
+        bits.write((self.distance_count - 1) as u32, 5);
+        bits.write((self.code_length_count - 4) as u32, 4);
+        for symbol in &CODE_LENGTH_ORDER[..self.code_length_count] {
+            bits.write(u32::from(self.code_lengths[*symbol]), 3);
+        }
+
+        let codes = canonical_codes(&self.code_lengths);
+        for (symbol, extra) in &self.runs {
+            bits.write(
+                u32::from(codes[*symbol]),
+                u32::from(self.code_lengths[*symbol]),
+            );
+            bits.write(*extra, run_extra_bits(*symbol));
+        }
+    }
+}
+
+fn run_extra_bits(symbol: usize) -> u32 {
+    match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    }
+}
+
+// Huffman code lengths for the given symbol frequencies, no longer than `limit`
+//
+// At least two symbols always get a code so that the code is complete, which
+// decoders handle more uniformly than the single-code special case.
+pub(crate) fn huffman_lengths(freqs: &[u32], limit: u8) -> Vec<u8> {
+    let mut symbols: Vec<(u32, usize)> = freqs
+        .iter()
+        .enumerate()
+        .filter(|(_, freq)| **freq > 0)
+        .map(|(symbol, freq)| (*freq, symbol))
+        .collect();
+    let mut unused = freqs.iter().enumerate().filter(|(_, freq)| **freq == 0);
+    while symbols.len() < 2 {
+        match unused.next() {
+            Some((symbol, _)) => symbols.push((1, symbol)),
+            None => break,
+        }
+    }
+
+    // Build the tree bottom-up; nodes below `symbols.len()` are leaves
+    let mut parents = vec![usize::MAX; symbols.len()];
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = symbols
+        .iter()
+        .enumerate()
+        .map(|(node, (freq, _))| Reverse((u64::from(*freq), node)))
+        .collect();
+    while heap.len() > 1 {
+        let Reverse((first_weight, first)) = heap.pop().unwrap_or_default();
+        let Reverse((second_weight, second)) = heap.pop().unwrap_or_default();
+        let parent = parents.len();
+        parents.push(usize::MAX);
+        parents[first] = parent;
+        parents[second] = parent;
+        heap.push(Reverse((first_weight + second_weight, parent)));
+    }
+
+    // Depth of every node, computed top-down since parents are created after their children
+    let mut depths = vec![0u32; parents.len()];
+    for node in (0..parents.len()).rev() {
+        if parents[node] != usize::MAX {
+            depths[node] = depths[parents[node]] + 1;
+        }
+    }
+
+    let mut lengths = vec![0u8; freqs.len()];
+    let max_depth = depths[..symbols.len()].iter().copied().max().unwrap_or(0);
+    if max_depth <= u32::from(limit) {
+        for (node, (_, symbol)) in symbols.iter().enumerate() {
+            lengths[*symbol] = depths[node] as u8;
+        }
+        return lengths;
+    }
+
+    // Too deep: clamp, then lengthen shorter codes until the Kraft sum is exact again
+    let limit = usize::from(limit);
+    let mut counts = vec![0u32; limit + 1];
+    for depth in &depths[..symbols.len()] {
+        counts[(*depth as usize).min(limit)] += 1;
+    }
+    let mut total: u64 = (1..=limit)
+        .map(|length| u64::from(counts[length]) << (limit - length))
+        .sum();
+    while total > 1 << limit {
+        counts[limit] -= 1;
+        if let Some(length) = (1..limit).rev().find(|length| counts[*length] != 0) {
+            counts[length] -= 1;
+            counts[length + 1] += 2;
+        }
+        total -= 1;
+    }
+
+    // Most frequent symbols get the shortest codes
+    symbols.sort_by_key(|(freq, symbol)| (Reverse(*freq), *symbol));
+    let mut ordered = symbols.iter();
+    for (length, count) in counts.iter().enumerate().skip(1) {
+        for _ in 0..*count {
+            if let Some((_, symbol)) = ordered.next() {
+                lengths[*symbol] = length as u8;
+            }
+        }
+    }
+    lengths
+}
+
+// Canonical Huffman codes for the given lengths, bit-reversed for LSB-first output
+pub(crate) fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; 16];
+    for length in lengths {
+        counts[usize::from(*length)] += 1;
+    }
+    counts[0] = 0;
+
+    let mut next = [0u16; 16];
+    let mut code = 0u16;
+    for length in 1..16 {
+        code = (code + counts[length - 1]) << 1;
+        next[length] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|length| {
+            let length = usize::from(*length);
+            if length == 0 {
+                return 0;
+            }
+            let code = next[length];
+            next[length] += 1;
+            code.reverse_bits() >> (16 - length)
+        })
+        .collect()
+}
+
+// Running CRC-32 as used by gzip; the final value is the complement of the state
+pub(crate) fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc = CRC_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+// Running Adler-32 as used by zlib, kept as its two sums
+pub(crate) fn adler32_update((mut a, mut b): (u32, u32), data: &[u8]) -> (u32, u32) {
+    // 5552 is the most bytes that can be summed before b could overflow
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (a, b)
+}
+
+pub(crate) fn adler32_finish((a, b): (u32, u32)) -> u32 {
+    b << 16 | a
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 != 0 {
+                0xedb8_8320 ^ (value >> 1)
+            } else {
+                value >> 1
+            };
+            bit += 1;
+        }
+        table[index] = value;
+        index += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::decompress;
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x9e37_79b9_u32;
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            match state >> 30 {
+                0 => data.push((state >> 8) as u8),
+                1 => data.extend_from_slice(b"<li class=\"item\">"),
+                2 => data.extend_from_slice(format!("{}", state % 1000).as_bytes()),
+                _ => data.extend_from_slice(b"</li>\n"),
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn computes_standard_checksums() {
+        assert_eq!(!crc32_update(CRC32_INIT, b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            adler32_finish(adler32_update(ADLER32_INIT, b"123456789")),
+            0x091e_01de
+        );
+        // Adler-32 sums are reduced often enough not to overflow on long input
+        let data = vec![0xff; 1 << 20];
+        let split = adler32_update(adler32_update(ADLER32_INIT, &data[..7]), &data[7..]);
+        assert_eq!(split, adler32_update(ADLER32_INIT, &data));
+    }
+
+    #[test]
+    fn writes_container_headers_and_trailers() {
+        let gzip = compress(b"abc", Format::Gzip);
+        assert_eq!(gzip[..10], GZIP_HEADER);
+        assert_eq!(gzip[gzip.len() - 4..], 3u32.to_le_bytes());
+
+        let zlib = compress(b"abc", Format::Zlib);
+        assert_eq!(zlib[..2], ZLIB_HEADER);
+        let adler = adler32_finish(adler32_update(ADLER32_INIT, b"abc"));
+        assert_eq!(zlib[zlib.len() - 4..], adler.to_be_bytes());
+    }
+
+    #[test]
+    fn compresses_repetitive_input() {
+        let data = sample(256 * 1024);
+        let compressed = compress(&data, Format::Gzip);
+        assert!(
+            compressed.len() < data.len() / 3,
+            "{} bytes",
+            compressed.len()
+        );
+        assert_eq!(
+            decompress(&compressed, Format::Gzip, data.len()).unwrap(),
+            data
+        );
+
+        let zeros = vec![0; 1 << 20];
+        assert!(compress(&zeros, Format::Zlib).len() < 2048);
+    }
+
+    #[test]
+    fn stores_incompressible_input_with_little_overhead() {
+        let mut state = 1u64;
+        let noise: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state ^= state << 7;
+                state ^= state >> 9;
+                state as u8
+            })
+            .collect();
+        let compressed = compress(&noise, Format::Zlib);
+        assert!(compressed.len() < noise.len() + 64);
+        assert_eq!(
+            decompress(&compressed, Format::Zlib, noise.len()).unwrap(),
+            noise
+        );
+    }
+
+    #[test]
+    fn flushes_mid_stream() {
+        let data = sample(100_000);
+        let mut encoder = Encoder::new(Vec::new(), Format::Gzip);
+        let mut flushed = 0;
+        for piece in data.chunks(7_777) {
+            encoder.write_all(piece).unwrap();
+            encoder.flush().unwrap();
+            // A sync flush always ends on the empty stored block marker
+            assert!(encoder.get_ref().len() > flushed);
+            assert!(encoder.get_ref().ends_with(&[0x00, 0x00, 0xff, 0xff]));
+            flushed = encoder.get_ref().len();
+        }
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(
+            decompress(&compressed, Format::Gzip, data.len()).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn limits_huffman_code_lengths() {
+        // Fibonacci frequencies give the deepest unrestricted trees
+        let mut freqs = vec![1u32, 1];
+        while freqs.len() < 30 {
+            freqs.push(freqs[freqs.len() - 1] + freqs[freqs.len() - 2]);
+        }
+        let lengths = huffman_lengths(&freqs, MAX_CODE_LENGTH);
+        assert!(lengths
+            .iter()
+            .all(|length| (1..=MAX_CODE_LENGTH).contains(length)));
+        // The code is complete: the Kraft sum is exactly one
+        let kraft: u32 = lengths
+            .iter()
+            .map(|length| 1 << (MAX_CODE_LENGTH - length))
+            .sum();
+        assert_eq!(kraft, 1 << MAX_CODE_LENGTH);
+
+        let single = huffman_lengths(&[0, 5, 0], MAX_CODE_LENGTH);
+        assert_eq!(single.iter().filter(|length| **length > 0).count(), 2);
+    }
+}
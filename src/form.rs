@@ -0,0 +1,614 @@
+use crate::request::{Limits, Request};
+use crate::url::{percent_decode, QueryParams};
+use http::StatusCode;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Longest header section accepted for one multipart part
+const MAX_PART_HEADER_SIZE: usize = 8 * 1024;
+
+// Most header fields accepted in one multipart part
+const MAX_PART_HEADERS: usize = 16;
+
+// Longest boundary RFC 2046 allows
+const MAX_BOUNDARY_LENGTH: usize = 70;
+
+// Longest file name kept after sanitising, in bytes
+const MAX_FILENAME_LENGTH: usize = 255;
+
+// Input read from the body per parsing step
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+// Limits and storage for parsing a form body
+//
+// The body is read into memory in full before any handler runs, so what a
+// form can hold is bounded by the server's `Limits::max_body_size` first. The
+// limits here only narrow that; a file limit above the body limit is lowered
+// to it when the server's `Limits` are registered as application state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormOptions {
+    // Most text fields and files together
+    pub max_parts: usize,
+    // Largest value of a single text field
+    pub max_field_size: usize,
+    // Largest single uploaded file, at most the server's body size limit
+    pub max_file_size: u64,
+    // Where uploaded files are written; they are removed again when dropped
+    pub upload_dir: PathBuf,
+}
+
+impl Default for FormOptions {
+    fn default() -> Self {
+        FormOptions {
+            max_parts: 128,
+            max_field_size: 64 * 1024,
+            max_file_size: Limits::default().max_body_size as u64,
+            upload_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+// Errors raised while parsing a form body
+#[derive(Debug)]
+pub enum FormError {
+    // Neither urlencoded nor multipart/form-data
+    UnsupportedMediaType,
+    MissingBoundary,
+    Malformed(&'static str),
+    InvalidEncoding,
+    TooManyParts,
+    FieldTooLarge,
+    FileTooLarge,
+    // Storing an uploaded file failed
+    Io(io::Error),
+}
+
+impl FormError {
+    // Status code to answer the request with
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            FormError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            FormError::MissingBoundary | FormError::Malformed(_) | FormError::InvalidEncoding => {
+                StatusCode::BAD_REQUEST
+            }
+            FormError::TooManyParts | FormError::FieldTooLarge | FormError::FileTooLarge => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            FormError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::UnsupportedMediaType => write!(
+                f,
+                "expected application/x-www-form-urlencoded or multipart/form-data"
+            ),
+            FormError::MissingBoundary => write!(f, "multipart body without a valid boundary"),
+            FormError::Malformed(message) => write!(f, "malformed multipart body: {}", message),
+            FormError::InvalidEncoding => write!(f, "form field is not valid UTF-8"),
+            FormError::TooManyParts => write!(f, "too many form fields"),
+            FormError::FieldTooLarge => write!(f, "form field too large"),
+            FormError::FileTooLarge => write!(f, "uploaded file too large"),
+            FormError::Io(err) => write!(f, "failed to store uploaded file: {}", err),
+        }
+    }
+}
+
+impl Error for FormError {}
+
+// Decoded form: text fields in submission order plus uploaded files
+#[derive(Debug, Default)]
+pub struct Form {
+    pub fields: QueryParams,
+    pub files: Vec<UploadedFile>,
+}
+
+impl Form {
+    // First value of a text field
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name)
+    }
+
+    // First file uploaded under a field name
+    pub fn file(&self, name: &str) -> Option<&UploadedFile> {
+        self.files.iter().find(|file| file.name == name)
+    }
+}
+
+// A file part stored in the upload directory
+//
+// The file is deleted when this is dropped unless it has been persisted.
+#[derive(Debug)]
+pub struct UploadedFile {
+    // Form field the file was sent under
+    pub name: String,
+    // Client-supplied file name, sanitised into a single safe path component
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub size: u64,
+    path: PathBuf,
+}
+
+impl UploadedFile {
+    // Location of the stored contents
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+
+    // Move the file to a permanent location, keeping it after this is dropped
+    pub fn persist(mut self, destination: &Path) -> io::Result<()> {
+        // Renaming fails across file systems; copy instead
+        if fs::rename(&self.path, destination).is_err() {
+            fs::copy(&self.path, destination)?;
+            let _ = fs::remove_file(&self.path);
+        }
+        self.path = PathBuf::new();
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Parse a urlencoded or multipart/form-data request body
+//
+// The server's `Limits`, when registered as application state, cap
+// `max_file_size` at the body size limit.
+pub fn parse(request: &Request, options: &FormOptions) -> Result<Form, FormError> {
+    let clamped;
+    let options = match request.state::<Limits>() {
+        Some(limits) if options.max_file_size > limits.max_body_size as u64 => {
+            clamped = FormOptions {
+                max_file_size: limits.max_body_size as u64,
+                ..options.clone()
+            };
+            &clamped
+        }
+        _ => options,
+    };
+
+    let content_type = request
+        .header("content-type")
+        .ok_or(FormError::UnsupportedMediaType)?;
+    let (media_type, parameters) = split_parameters(content_type);
+
+    match media_type.to_ascii_lowercase().as_str() {
+        "application/x-www-form-urlencoded" => {
+            let text =
+                std::str::from_utf8(&request.body).map_err(|_| FormError::InvalidEncoding)?;
+            let fields = QueryParams::parse(text).map_err(|_| FormError::InvalidEncoding)?;
+            if fields.len() > options.max_parts {
+                return Err(FormError::TooManyParts);
+            }
+            if fields
+                .iter()
+                .any(|(_, value)| value.len() > options.max_field_size)
+            {
+                return Err(FormError::FieldTooLarge);
+            }
+            Ok(Form {
+                fields,
+                files: Vec::new(),
+            })
+        }
+        "multipart/form-data" => {
+            let boundary = parameter(&parameters, "boundary")
+                .filter(|boundary| !boundary.is_empty() && boundary.len() <= MAX_BOUNDARY_LENGTH)
+                .ok_or(FormError::MissingBoundary)?;
+            parse_multipart(request.body.as_slice(), boundary, options)
+        }
+        _ => Err(FormError::UnsupportedMediaType),
+    }
+}
+
+// Parse a multipart/form-data body as it is read, writing file parts straight to disk
+pub fn parse_multipart<R: Read>(
+    source: R,
+    boundary: &str,
+    options: &FormOptions,
+) -> Result<Form, FormError> {
+    let mut reader = MultipartReader::new(source, boundary);
+    let mut fields = Vec::new();
+    let mut files = Vec::new();
+
+    // Everything before the first delimiter is preamble
+    reader.read_content(|_| Ok(()))?;
+    while reader.next_part()? {
+        if fields.len() + files.len() >= options.max_parts {
+            return Err(FormError::TooManyParts);
+        }
+
+        let headers = reader.read_headers()?;
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        let (disposition, parameters) = split_parameters(
+            header("content-disposition")
+                .ok_or(FormError::Malformed("part without Content-Disposition"))?,
+        );
+        if !disposition.eq_ignore_ascii_case("form-data") {
+            return Err(FormError::Malformed("part is not form-data"));
+        }
+        let name = parameter(&parameters, "name")
+            .ok_or(FormError::Malformed("part without a field name"))?
+            .to_string();
+        let content_type = header("content-type").map(str::to_string);
+
+        // RFC 5987 `filename*` takes precedence over the plain parameter
+        let filename = parameter(&parameters, "filename*")
+            .and_then(decode_extended)
+            .or_else(|| parameter(&parameters, "filename").map(str::to_string));
+        let filename = match filename {
+            Some(filename) => filename,
+            None => {
+                let mut value = Vec::new();
+                reader.read_content(|data| {
+                    if value.len() + data.len() > options.max_field_size {
+                        return Err(FormError::FieldTooLarge);
+                    }
+                    value.extend_from_slice(data);
+                    Ok(())
+                })?;
+                let value = String::from_utf8(value).map_err(|_| FormError::InvalidEncoding)?;
+                fields.push((name, value));
+                continue;
+            }
+        };
+
+        let (path, mut file) = create_upload_file(&options.upload_dir).map_err(FormError::Io)?;
+        // Owns the path from here on, so the file is removed if the part turns out bad
+        let mut upload = UploadedFile {
+            name,
+            filename: sanitize_filename(&filename),
+            content_type,
+            size: 0,
+            path,
+        };
+        let mut size = 0;
+        reader.read_content(|data| {
+            size += data.len() as u64;
+            if size > options.max_file_size {
+                return Err(FormError::FileTooLarge);
+            }
+            file.write_all(data).map_err(FormError::Io)
+        })?;
+        file.flush().map_err(FormError::Io)?;
+        upload.size = size;
+        files.push(upload);
+    }
+
+    Ok(Form {
+        fields: fields.into_iter().collect(),
+        files,
+    })
+}
+
+// Incremental reader over the parts of a multipart body
+struct MultipartReader<R> {
+    source: R,
+    buffer: Vec<u8>,
+    // `CRLF--boundary`, which ends every part
+    delimiter: Vec<u8>,
+}
+
+impl<R: Read> MultipartReader<R> {
+    fn new(source: R, boundary: &str) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        MultipartReader {
+            source,
+            // The first delimiter may open the body without a preceding CRLF
+            buffer: b"\r\n".to_vec(),
+            delimiter,
+        }
+    }
+
+    // Read more input, returning false once the body is exhausted
+    fn fill(&mut self) -> Result<bool, FormError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let read = self.source.read(&mut chunk).map_err(FormError::Io)?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    // Pass the content up to the next delimiter to `sink` and consume the delimiter
+    fn read_content<F>(&mut self, mut sink: F) -> Result<(), FormError>
+    where
+        F: FnMut(&[u8]) -> Result<(), FormError>,
+    {
+        loop {
+            if let Some(position) = find(&self.buffer, &self.delimiter) {
+                sink(&self.buffer[..position])?;
+                self.buffer.drain(..position + self.delimiter.len());
+                return Ok(());
+            }
+
+            // Hold back a tail that could be the start of a delimiter split across reads
+            let keep = (self.delimiter.len() - 1).min(self.buffer.len());
+            let ready = self.buffer.len() - keep;
+            if ready > 0 {
+                sink(&self.buffer[..ready])?;
+                self.buffer.drain(..ready);
+            }
+            if !self.fill()? {
+                return Err(FormError::Malformed(
+                    "body ends before the closing boundary",
+                ));
+            }
+        }
+    }
+
+    // After a delimiter, whether another part follows rather than the end of the body
+    fn next_part(&mut self) -> Result<bool, FormError> {
+        loop {
+            if self.buffer.starts_with(b"--") {
+                return Ok(false);
+            }
+            // Senders may pad the delimiter line with whitespace
+            let padding = self
+                .buffer
+                .iter()
+                .take_while(|byte| matches!(byte, b' ' | b'\t'))
+                .count();
+            if self.buffer[padding..].starts_with(b"\r\n") {
+                self.buffer.drain(..padding + 2);
+                return Ok(true);
+            }
+            if self.buffer.len() > padding + 1 || padding > MAX_PART_HEADER_SIZE {
+                return Err(FormError::Malformed("invalid boundary line"));
+            }
+            if !self.fill()? {
+                return Err(FormError::Malformed("body ends after a boundary"));
+            }
+        }
+    }
+
+    // Read a part's header fields, up to and including the blank line
+    fn read_headers(&mut self) -> Result<Vec<(String, String)>, FormError> {
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; MAX_PART_HEADERS];
+            let parsed = match httparse::parse_headers(&self.buffer, &mut headers) {
+                Ok(httparse::Status::Complete((consumed, parsed))) => Some((
+                    consumed,
+                    parsed
+                        .iter()
+                        .map(|header| {
+                            String::from_utf8(header.value.to_vec())
+                                .map(|value| (header.name.to_string(), value))
+                                .map_err(|_| FormError::InvalidEncoding)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                )),
+                Ok(httparse::Status::Partial) => None,
+                Err(_) => return Err(FormError::Malformed("invalid part headers")),
+            };
+
+            if let Some((consumed, headers)) = parsed {
+                self.buffer.drain(..consumed);
+                return Ok(headers);
+            }
+            if self.buffer.len() > MAX_PART_HEADER_SIZE {
+                return Err(FormError::Malformed("part headers too large"));
+            }
+            if !self.fill()? {
+                return Err(FormError::Malformed("body ends inside part headers"));
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+// Split a header value such as `form-data; name="a"; filename="b;c.txt"` into its
+// leading token and its parameters, with quoted values unquoted
+fn split_parameters(value: &str) -> (String, Vec<(String, String)>) {
+    let mut segments = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let segment = segments.last_mut().unwrap();
+        match c {
+            '"' => quoted = !quoted,
+            // Browsers leave backslashes in file names unescaped, so only `\"` and `\\` are escapes
+            '\\' if quoted && matches!(chars.peek(), Some('"' | '\\')) => {
+                segment.extend(chars.next());
+            }
+            ';' if !quoted => segments.push(String::new()),
+            c if c.is_whitespace() && !quoted => {}
+            c => segment.push(c),
+        }
+    }
+
+    let mut segments = segments.into_iter();
+    let first = segments.next().unwrap_or_default();
+    let parameters = segments
+        .filter_map(|segment| {
+            let (name, value) = segment.split_once('=')?;
+            Some((name.to_ascii_lowercase(), value.to_string()))
+        })
+        .collect();
+    (first, parameters)
+}
+
+fn parameter<'a>(parameters: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    parameters
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+// Decode an RFC 5987 value such as `UTF-8''na%C3%AFve.txt`
+fn decode_extended(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let _language = parts.next()?;
+    let encoded = parts.next()?;
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+    percent_decode(encoded, false).ok()
+}
+
+// Reduce a client-supplied file name to a single path component that is safe to store
+//
+// Directories (with either separator), control characters, characters Windows
+// reserves and leading dots are dropped. Returns `None` when nothing usable is left.
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && !matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|'))
+        .collect();
+    let cleaned = cleaned
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' ']);
+
+    let mut end = cleaned.len().min(MAX_FILENAME_LENGTH);
+    while !cleaned.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cleaned = &cleaned[..end];
+    if cleaned.is_empty() {
+        return None;
+    }
+    Some(cleaned.to_string())
+}
+
+// Create a new, uniquely named file in the upload directory
+fn create_upload_file(dir: &Path) -> io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    loop {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        let path = dir.join(format!(
+            "upload-{}-{:016x}",
+            std::process::id(),
+            hasher.finish()
+        ));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        match options.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AppState;
+    use std::sync::Arc;
+
+    const BOUNDARY: &str = "XyZ";
+
+    fn request(content_type: &str, body: &[u8], limits: Option<Limits>) -> Request {
+        let mut request =
+            Request::for_test("POST", "/submit", &[("Content-Type", content_type)], body);
+        if let Some(limits) = limits {
+            let mut state = AppState::new();
+            state.insert(limits);
+            request.app_state = Arc::new(state);
+        }
+        request
+    }
+
+    fn multipart(file: &[u8], limits: Option<Limits>) -> Request {
+        let mut body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nhello\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"../a.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n",
+            b = BOUNDARY
+        )
+        .into_bytes();
+        body.extend_from_slice(file);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+        request(&content_type, &body, limits)
+    }
+
+    #[test]
+    fn parses_urlencoded_fields() {
+        let request = request(
+            "application/x-www-form-urlencoded",
+            b"a=1&b=two+words&a=3",
+            None,
+        );
+        let form = request.form().unwrap();
+        assert_eq!(form.field("a"), Some("1"));
+        assert_eq!(form.field("b"), Some("two words"));
+        assert!(form.files.is_empty());
+    }
+
+    #[test]
+    fn stores_uploaded_files() {
+        let form = multipart(b"file contents", None).form().unwrap();
+        assert_eq!(form.field("title"), Some("hello"));
+        let file = form.file("upload").unwrap();
+        assert_eq!(file.filename.as_deref(), Some("a.txt"));
+        assert_eq!(file.size, 13);
+        assert_eq!(fs::read(file.path()).unwrap(), b"file contents");
+    }
+
+    #[test]
+    fn enforces_the_file_size_limit() {
+        let options = FormOptions {
+            max_file_size: 4,
+            ..FormOptions::default()
+        };
+        assert!(matches!(
+            multipart(b"12345", None).form_with(&options),
+            Err(FormError::FileTooLarge)
+        ));
+    }
+
+    #[test]
+    fn clamps_the_file_limit_to_the_body_limit() {
+        let limits = Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        };
+        let request = multipart(b"12345", Some(limits));
+        let err = request.form_with(&FormOptions::default()).unwrap_err();
+        assert!(matches!(err, FormError::FileTooLarge));
+        assert_eq!(err.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(multipart(b"12345", None)
+            .form_with(&FormOptions::default())
+            .is_ok());
+    }
+}
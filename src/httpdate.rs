@@ -0,0 +1,145 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Format a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    let seconds_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAY_NAMES[(days % 7) as usize],
+        day,
+        MONTH_NAMES[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+// Parse an IMF-fixdate; the obsolete RFC 850 and asctime forms are not accepted
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let rest = value.trim().split_once(", ")?.1;
+    let mut parts = rest.split(' ');
+
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTH_NAMES.iter().position(|name| *name == month_name)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut clock = parts.next()?.split(':');
+    let hour: u64 = clock.next()?.parse().ok()?;
+    let minute: u64 = clock.next()?.parse().ok()?;
+    let second: u64 = clock.next()?.parse().ok()?;
+    if parts.next()? != "GMT" || parts.next().is_some() || clock.next().is_some() {
+        return None;
+    }
+    // Four-digit years are all the format allows, which also keeps the arithmetic in range
+    if !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+        || !(1970..=9999).contains(&year)
+    {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Truncate a time to whole seconds, the resolution of HTTP dates
+pub fn truncate(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+// Convert days since 1970-01-01 to a (year, month, day) date (Howard Hinnant's algorithm)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+// Convert a (year, month, day) date to days since 1970-01-01
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_and_parses_imf_fixdate() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(format(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+    }
+
+    #[test]
+    fn round_trips_leap_days() {
+        let date = "Thu, 29 Feb 2024 23:59:59 GMT";
+        assert_eq!(parse(date).map(format).as_deref(), Some(date));
+    }
+
+    #[test]
+    fn rejects_malformed_dates() {
+        for value in [
+            "",
+            "Sun, 06 Nov 1994 08:49:37",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Foo 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            "Thu, 01 Jan 1969 00:00:00 GMT",
+        ] {
+            assert_eq!(parse(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_years_beyond_four_digits_without_overflowing() {
+        assert!(parse("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+        assert_eq!(parse("Sat, 01 Jan 10000 00:00:00 GMT"), None);
+        assert_eq!(parse("Sun, 01 Jan 300000000000 00:00:00 GMT"), None);
+        assert_eq!(parse("Sun, 01 Jan 9000000000000000000 00:00:00 GMT"), None);
+    }
+
+    #[test]
+    fn truncates_to_whole_seconds() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_500);
+        assert_eq!(truncate(time), UNIX_EPOCH + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,511 @@
+use crate::deflate::{
+    adler32_finish, adler32_update, crc32_update, fixed_lengths, Format, ADLER32_INIT,
+    CODE_LENGTH_ORDER, CRC32_INIT, DISTANCE_BASE, DISTANCE_EXTRA, END_OF_BLOCK, LENGTH_BASE,
+    LENGTH_EXTRA,
+};
+use std::error::Error;
+use std::fmt;
+
+const MAX_CODE_LENGTH: usize = 15;
+
+// gzip header flags
+const FLAG_HEADER_CRC: u8 = 0x02;
+const FLAG_EXTRA: u8 = 0x04;
+const FLAG_NAME: u8 = 0x08;
+const FLAG_COMMENT: u8 = 0x10;
+const FLAG_RESERVED: u8 = 0xe0;
+
+// Errors raised while decompressing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InflateError {
+    Corrupt(&'static str),
+    // The output would exceed the caller's limit
+    TooLarge,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InflateError::Corrupt(message) => write!(f, "corrupt compressed data: {}", message),
+            InflateError::TooLarge => write!(f, "decompressed data exceeds the size limit"),
+        }
+    }
+}
+
+impl Error for InflateError {}
+
+type Result<T> = std::result::Result<T, InflateError>;
+
+// Decompress a complete gzip or zlib stream, producing at most `limit` bytes
+//
+// Concatenated gzip members are decoded in sequence. Because some clients send
+// raw deflate data for the `deflate` coding, Zlib input without a valid zlib
+// header is decoded as raw deflate.
+pub fn decompress(data: &[u8], format: Format, limit: usize) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    match format {
+        Format::Gzip => {
+            let mut rest = data;
+            loop {
+                rest = gunzip_member(rest, &mut output, limit)?;
+                // Some tools pad the file with zeros after the last member
+                if rest.iter().all(|byte| *byte == 0) {
+                    break;
+                }
+            }
+        }
+        Format::Zlib => {
+            let has_header = data.len() >= 2
+                && data[0] & 0x0f == 8
+                && data[0] >> 4 <= 7
+                && (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 == 0;
+            if !has_header {
+                inflate(data, &mut output, limit)?;
+                return Ok(output);
+            }
+            if data[1] & 0x20 != 0 {
+                return Err(InflateError::Corrupt(
+                    "preset dictionaries are not supported",
+                ));
+            }
+
+            let consumed = 2 + inflate(&data[2..], &mut output, limit)?;
+            let trailer = data
+                .get(consumed..consumed + 4)
+                .ok_or(InflateError::Corrupt("missing Adler-32 checksum"))?;
+            let expected = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            if adler32_finish(adler32_update(ADLER32_INIT, &output)) != expected {
+                return Err(InflateError::Corrupt("Adler-32 checksum mismatch"));
+            }
+        }
+    }
+    Ok(output)
+}
+
+// Decode one gzip member onto `output` and return the input after it
+fn gunzip_member<'a>(data: &'a [u8], output: &mut Vec<u8>, limit: usize) -> Result<&'a [u8]> {
+    let truncated = InflateError::Corrupt("truncated gzip header");
+    if data.len() < 10 || data[0] != 0x1f || data[1] != 0x8b {
+        return Err(InflateError::Corrupt("not in gzip format"));
+    }
+    if data[2] != 8 {
+        return Err(InflateError::Corrupt("unknown gzip compression method"));
+    }
+    let flags = data[3];
+    if flags & FLAG_RESERVED != 0 {
+        return Err(InflateError::Corrupt("reserved gzip flags set"));
+    }
+
+    let mut position = 10;
+    if flags & FLAG_EXTRA != 0 {
+        let length = data.get(position..position + 2).ok_or(truncated.clone())?;
+        position += 2 + usize::from(u16::from_le_bytes([length[0], length[1]]));
+    }
+    for flag in [FLAG_NAME, FLAG_COMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .get(position..)
+                .and_then(|rest| rest.iter().position(|byte| *byte == 0))
+                .ok_or(truncated.clone())?;
+            position += end + 1;
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        position += 2;
+    }
+    let body = data.get(position..).ok_or(truncated)?;
+
+    let start = output.len();
+    let consumed = position + inflate(body, output, limit)?;
+    let trailer = data
+        .get(consumed..consumed + 8)
+        .ok_or(InflateError::Corrupt("missing gzip trailer"))?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+    let member = &output[start..];
+    if !crc32_update(CRC32_INIT, member) != crc {
+        return Err(InflateError::Corrupt("CRC-32 mismatch"));
+    }
+    if member.len() as u32 != size {
+        return Err(InflateError::Corrupt("length mismatch"));
+    }
+    Ok(&data[consumed + 8..])
+}
+
+// Decode a raw deflate stream onto `output`, returning the number of input bytes used
+fn inflate(data: &[u8], output: &mut Vec<u8>, limit: usize) -> Result<usize> {
+    let mut bits = BitReader::new(data);
+
+    loop {
+        let last = bits.read(1)? == 1;
+        match bits.read(2)? {
+            0 => stored_block(&mut bits, output, limit)?,
+            1 => {
+                let (literals, distances) = fixed_lengths();
+                let literals = Huffman::new(&literals)?;
+                let distances = Huffman::new(&distances)?;
+                codes(&mut bits, output, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, output, limit, &literals, &distances)?;
+            }
+            _ => return Err(InflateError::Corrupt("invalid block type")),
+        }
+        if last {
+            break;
+        }
+    }
+
+    bits.align();
+    Ok(bits.consumed())
+}
+
+fn stored_block(bits: &mut BitReader, output: &mut Vec<u8>, limit: usize) -> Result<()> {
+    bits.align();
+    let length = bits.read(16)?;
+    let complement = bits.read(16)?;
+    if length != !complement & 0xffff {
+        return Err(InflateError::Corrupt("stored block length mismatch"));
+    }
+    if output.len() + length as usize > limit {
+        return Err(InflateError::TooLarge);
+    }
+    for _ in 0..length {
+        output.push(bits.read(8)? as u8);
+    }
+    Ok(())
+}
+
+// Read the code length tables that start a dynamic block
+fn dynamic_tables(bits: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literal_count = bits.read(5)? as usize + 257;
+    let distance_count = bits.read(5)? as usize + 1;
+    let code_length_count = bits.read(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(InflateError::Corrupt("too many length or distance codes"));
+    }
+
+    let mut code_lengths = [0u8; 19];
+    for symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[*symbol] = bits.read(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths)?;
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let symbol = bits.decode(&code_length_code)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths
+                    .last()
+                    .ok_or(InflateError::Corrupt("repeat with no previous length"))?;
+                (previous, 3 + bits.read(2)? as usize)
+            }
+            17 => (0, 3 + bits.read(3)? as usize),
+            _ => (0, 11 + bits.read(7)? as usize),
+        };
+        if lengths.len() + repeat > literal_count + distance_count {
+            return Err(InflateError::Corrupt("too many code lengths"));
+        }
+        lengths.extend(std::iter::repeat_n(length, repeat));
+    }
+
+    if lengths[END_OF_BLOCK] == 0 {
+        return Err(InflateError::Corrupt("missing end-of-block code"));
+    }
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..])?;
+    Ok((literals, distances))
+}
+
+// Decode literals and matches until the end-of-block code
+fn codes(
+    bits: &mut BitReader,
+    output: &mut Vec<u8>,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<()> {
+    loop {
+        let symbol = bits.decode(literals)?;
+        if symbol < END_OF_BLOCK {
+            if output.len() >= limit {
+                return Err(InflateError::TooLarge);
+            }
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+
+        let code = symbol - 257;
+        if code >= LENGTH_BASE.len() {
+            return Err(InflateError::Corrupt("invalid length code"));
+        }
+        let length =
+            usize::from(LENGTH_BASE[code]) + bits.read(u32::from(LENGTH_EXTRA[code]))? as usize;
+
+        let code = bits.decode(distances)?;
+        if code >= DISTANCE_BASE.len() {
+            return Err(InflateError::Corrupt("invalid distance code"));
+        }
+        let distance =
+            usize::from(DISTANCE_BASE[code]) + bits.read(u32::from(DISTANCE_EXTRA[code]))? as usize;
+        if distance > output.len() {
+            return Err(InflateError::Corrupt("distance too far back"));
+        }
+        if output.len() + length > limit {
+            return Err(InflateError::TooLarge);
+        }
+
+        // Copy byte by byte, as a match may overlap the bytes it produces
+        let start = output.len() - distance;
+        for index in start..start + length {
+            output.push(output[index]);
+        }
+    }
+}
+
+// Canonical Huffman code in the counts-and-symbols form used for bitwise decoding
+struct Huffman {
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; MAX_CODE_LENGTH + 1];
+        for length in lengths {
+            counts[usize::from(*length)] += 1;
+        }
+
+        // Refuse codes with more codes of some length than the shorter ones leave room for
+        let mut left: i32 = 1;
+        for count in &counts[1..] {
+            left = (left << 1) - i32::from(*count);
+            if left < 0 {
+                return Err(InflateError::Corrupt("over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_CODE_LENGTH + 1];
+        for length in 1..MAX_CODE_LENGTH {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                let offset = &mut offsets[usize::from(*length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+}
+
+// Reads bits least significant first from a byte slice
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u32> {
+        while self.count < bits {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or(InflateError::Corrupt("unexpected end of data"))?;
+            self.buffer |= u64::from(byte) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+        let value = (self.buffer & ((1 << bits) - 1)) as u32;
+        self.buffer >>= bits;
+        self.count -= bits;
+        Ok(value)
+    }
+
+    // Decode one symbol, reading the code a bit at a time
+    fn decode(&mut self, huffman: &Huffman) -> Result<usize> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for count in &huffman.counts[1..] {
+            code |= self.read(1)? as i32;
+            let count = i32::from(*count);
+            if code - first < count {
+                return Ok(usize::from(
+                    huffman.symbols[(index + code - first) as usize],
+                ));
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::Corrupt("invalid Huffman code"))
+    }
+
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.buffer >>= partial;
+        self.count -= partial;
+    }
+
+    // Input bytes used so far, not counting whole bytes read ahead into the buffer
+    fn consumed(&self) -> usize {
+        self.position - (self.count / 8) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deflate::compress;
+
+    // Text with repeats at varying distances mixed with noise, to exercise
+    // literals, short and long matches and dynamic blocks
+    fn sample(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        let words: [&[u8]; 4] = [b"alpha ", b"beta ", b"gamma delta ", b"\x00\xff"];
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if state.is_multiple_of(3) {
+                data.push(state as u8);
+            } else {
+                data.extend_from_slice(words[state as usize % words.len()]);
+            }
+        }
+        data.truncate(len);
+        data
+    }
+
+    #[test]
+    fn decodes_streams_from_other_encoders() {
+        let zlib = [
+            120, 218, 203, 72, 205, 201, 201, 87, 200, 64, 39, 1, 104, 3, 8, 177,
+        ];
+        assert_eq!(
+            decompress(&zlib, Format::Zlib, 1024).unwrap(),
+            b"hello hello hello hello"
+        );
+
+        let gzip = [
+            31, 139, 8, 0, 0, 0, 0, 0, 2, 3, 203, 72, 205, 201, 201, 87, 40, 207, 47, 202, 73, 1,
+            0, 133, 17, 74, 13, 11, 0, 0, 0,
+        ];
+        assert_eq!(
+            decompress(&gzip, Format::Gzip, 1024).unwrap(),
+            b"hello world"
+        );
+
+        // Raw deflate with a stored block, as some clients send for `deflate`
+        let raw = [1, 3, 0, 252, 255, 97, 98, 99];
+        assert_eq!(decompress(&raw, Format::Zlib, 1024).unwrap(), b"abc");
+    }
+
+    #[test]
+    fn round_trips_through_the_encoder() {
+        for len in [0, 1, 100, 40_000, 200_000] {
+            let data = sample(len);
+            for format in [Format::Zlib, Format::Gzip] {
+                let compressed = compress(&data, format);
+                assert_eq!(decompress(&compressed, format, len).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_concatenated_gzip_members() {
+        let mut data = compress(b"first ", Format::Gzip);
+        data.extend(compress(b"second", Format::Gzip));
+        data.extend([0; 4]);
+        assert_eq!(
+            decompress(&data, Format::Gzip, 1024).unwrap(),
+            b"first second"
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let data = sample(10_000);
+        for format in [Format::Zlib, Format::Gzip] {
+            let compressed = compress(&data, format);
+            for len in 0..compressed.len() {
+                assert!(
+                    decompress(&compressed[..len], format, data.len()).is_err(),
+                    "{:?} stream cut at {} bytes was accepted",
+                    format,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let data = sample(5_000);
+        let mut compressed = compress(&data, Format::Zlib);
+        let last = compressed.len() - 1;
+        compressed[last] ^= 1;
+        assert_eq!(
+            decompress(&compressed, Format::Zlib, data.len()),
+            Err(InflateError::Corrupt("Adler-32 checksum mismatch"))
+        );
+
+        let mut compressed = compress(&data, Format::Gzip);
+        let crc = compressed.len() - 8;
+        compressed[crc] ^= 1;
+        assert_eq!(
+            decompress(&compressed, Format::Gzip, data.len()),
+            Err(InflateError::Corrupt("CRC-32 mismatch"))
+        );
+
+        // Flipping any bit must be reported, or at worst decode to something, never panic
+        let compressed = compress(&data, Format::Zlib);
+        for index in 0..compressed.len() {
+            for bit in [0x01, 0x10, 0x80] {
+                let mut corrupt = compressed.clone();
+                corrupt[index] ^= bit;
+                let _ = decompress(&corrupt, Format::Zlib, data.len());
+            }
+        }
+        assert!(decompress(b"\x1f\x8b\x09", Format::Gzip, 1024).is_err());
+        assert!(decompress(&[0xff; 64], Format::Zlib, 1024).is_err());
+    }
+
+    #[test]
+    fn stops_at_the_output_limit() {
+        // Four MiB of zeros compress to a few kilobytes
+        let bomb = compress(&vec![0; 4 * 1024 * 1024], Format::Gzip);
+        assert!(bomb.len() < 16 * 1024);
+        assert_eq!(
+            decompress(&bomb, Format::Gzip, 1024 * 1024),
+            Err(InflateError::TooLarge)
+        );
+        assert_eq!(
+            decompress(&bomb, Format::Gzip, 4 * 1024 * 1024).map(|data| data.len()),
+            Ok(4 * 1024 * 1024)
+        );
+    }
+}
@@ -0,0 +1,856 @@
+use crate::response::Response;
+use http::{header, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+// Deepest nesting of arrays and objects accepted, which bounds parser recursion
+const MAX_DEPTH: usize = 128;
+
+// Largest magnitude below which every integer is exactly representable as f64
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+// A JSON value
+//
+// Numbers are held as f64, as in JavaScript, and objects keep their members in
+// document order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Value {
+    #[default]
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    // Build an object from `(key, value)` pairs
+    pub fn object<K, I>(entries: I) -> Value
+    where
+        K: Into<String>,
+        I: IntoIterator<Item = (K, Value)>,
+    {
+        Value::Object(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.into(), value))
+                .collect(),
+        )
+    }
+
+    // Member of an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    // Extract a typed member of an object; a missing member reads as null
+    pub fn field<T: FromJson>(&self, key: &str) -> Result<T, JsonError> {
+        if !matches!(self, Value::Object(_)) {
+            return Err(JsonError::expected("an object", self));
+        }
+        T::from_json(self.get(key).unwrap_or(&Value::Null))
+            .map_err(|err| err.within(&format!(".{}", key)))
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    // Name of the value's type, for error messages
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "a boolean",
+            Value::Number(_) => "a number",
+            Value::String(_) => "a string",
+            Value::Array(_) => "an array",
+            Value::Object(_) => "an object",
+        }
+    }
+}
+
+// Compact JSON text
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(value) => write!(f, "{}", value),
+            // JSON has no NaN or infinity
+            Value::Number(value) if !value.is_finite() => write!(f, "null"),
+            Value::Number(value) if value.fract() == 0.0 && value.abs() <= MAX_SAFE_INTEGER => {
+                write!(f, "{}", *value as i64)
+            }
+            // Debug gives the shortest round-tripping form, with an exponent for extremes
+            Value::Number(value) => write!(f, "{:?}", value),
+            Value::String(value) => write_string(f, value),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(members) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        let escape = match c {
+            '"' => "\\\"",
+            '\\' => "\\\\",
+            '\n' => "\\n",
+            '\r' => "\\r",
+            '\t' => "\\t",
+            '\u{08}' => "\\b",
+            '\u{0c}' => "\\f",
+            c if c < ' ' => "",
+            _ => continue,
+        };
+        f.write_str(&text[start..index])?;
+        if escape.is_empty() {
+            write!(f, "\\u{:04x}", c as u32)?;
+        } else {
+            f.write_str(escape)?;
+        }
+        start = index + c.len_utf8();
+    }
+    f.write_str(&text[start..])?;
+    write!(f, "\"")
+}
+
+// Errors from parsing JSON or extracting typed values from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonError {
+    // The request body is not declared as JSON
+    UnsupportedMediaType,
+    // Malformed JSON, with the byte offset where parsing stopped
+    Syntax {
+        offset: usize,
+        message: &'static str,
+    },
+    // Well-formed JSON of the wrong shape, with the path to the offending value
+    Invalid {
+        path: String,
+        message: String,
+    },
+}
+
+impl JsonError {
+    // Error for a value of the wrong type
+    pub fn expected(what: &str, found: &Value) -> Self {
+        JsonError::Invalid {
+            path: String::new(),
+            message: format!("expected {}, found {}", what, found.kind()),
+        }
+    }
+
+    // Prefix the path of a shape error with the member or index it was found under
+    fn within(self, segment: &str) -> Self {
+        match self {
+            JsonError::Invalid { path, message } => JsonError::Invalid {
+                path: format!("{}{}", segment, path),
+                message,
+            },
+            other => other,
+        }
+    }
+
+    // Status code to answer the request with
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            JsonError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            JsonError::Syntax { .. } | JsonError::Invalid { .. } => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    // RFC 9457 problem details response describing the error
+    pub fn to_response(&self) -> Response {
+        let status = self.status_code();
+        let problem = Value::object([
+            ("type", Value::String("about:blank".to_string())),
+            (
+                "title",
+                status.canonical_reason().unwrap_or("Error").to_json(),
+            ),
+            ("status", status.as_u16().to_json()),
+            ("detail", self.to_string().to_json()),
+        ]);
+        Response::new(status)
+            .header(header::CONTENT_TYPE, "application/problem+json")
+            .body(problem.to_string())
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::UnsupportedMediaType => write!(f, "expected an application/json body"),
+            JsonError::Syntax { offset, message } => {
+                write!(f, "invalid JSON at byte {}: {}", offset, message)
+            }
+            JsonError::Invalid { path, message } => write!(f, "${}: {}", path, message),
+        }
+    }
+}
+
+impl Error for JsonError {}
+
+// Whether a Content-Type names JSON, including `+json` types such as application/problem+json
+pub fn is_json_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime == "application/json" || (mime.starts_with("application/") && mime.ends_with("+json"))
+}
+
+// Parse a complete JSON document
+pub fn parse(input: &str) -> Result<Value, JsonError> {
+    let mut parser = Parser {
+        input: input.as_bytes(),
+        text: input,
+        position: 0,
+        depth: 0,
+    };
+    parser.skip_whitespace();
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position < parser.input.len() {
+        return Err(parser.error("unexpected characters after the value"));
+    }
+    Ok(value)
+}
+
+// Parse a JSON document from bytes that should be UTF-8
+pub fn parse_bytes(input: &[u8]) -> Result<Value, JsonError> {
+    let text = std::str::from_utf8(input).map_err(|err| JsonError::Syntax {
+        offset: err.valid_up_to(),
+        message: "invalid UTF-8",
+    })?;
+    parse(text)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    text: &'a str,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &'static str) -> JsonError {
+        JsonError::Syntax {
+            offset: self.position,
+            message,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        if self.peek() == Some(byte) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, JsonError> {
+        match self.peek() {
+            Some(b'{') => self.nested(Parser::object),
+            Some(b'[') => self.nested(Parser::array),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("expected a value")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn nested(
+        &mut self,
+        parse: fn(&mut Self) -> Result<Value, JsonError>,
+    ) -> Result<Value, JsonError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, JsonError> {
+        if !self.input[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error("expected a value"));
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn object(&mut self) -> Result<Value, JsonError> {
+        self.position += 1;
+        let mut members: Vec<(String, Value)> = Vec::new();
+        // Position of each key in `members`, so duplicates are found in constant time
+        let mut index: HashMap<String, usize> = HashMap::new();
+        self.skip_whitespace();
+        if self.eat(b'}') {
+            return Ok(Value::Object(members));
+        }
+
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a string key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            if !self.eat(b':') {
+                return Err(self.error("expected ':' after key"));
+            }
+            self.skip_whitespace();
+            let value = self.value()?;
+
+            // The last of duplicate keys wins, as in most parsers
+            match index.get(&key) {
+                Some(&at) => members[at].1 = value,
+                None => {
+                    index.insert(key.clone(), members.len());
+                    members.push((key, value));
+                }
+            }
+
+            self.skip_whitespace();
+            if self.eat(b'}') {
+                return Ok(Value::Object(members));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected ',' or '}'"));
+            }
+            self.skip_whitespace();
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, JsonError> {
+        self.position += 1;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.eat(b']') {
+            return Ok(Value::Array(values));
+        }
+
+        loop {
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(b']') {
+                return Ok(Value::Array(values));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected ',' or ']'"));
+            }
+            self.skip_whitespace();
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.position += 1;
+        let mut text = String::new();
+        let mut start = self.position;
+
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    text.push_str(&self.text[start..self.position]);
+                    self.position += 1;
+                    return Ok(text);
+                }
+                Some(b'\\') => {
+                    text.push_str(&self.text[start..self.position]);
+                    self.position += 1;
+                    text.push(self.escape()?);
+                    start = self.position;
+                }
+                Some(byte) if byte < 0x20 => {
+                    return Err(self.error("control character in string"));
+                }
+                Some(_) => self.position += 1,
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, JsonError> {
+        let byte = self
+            .peek()
+            .ok_or_else(|| self.error("unterminated string"))?;
+        self.position += 1;
+        let c = match byte {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{08}',
+            b'f' => '\u{0c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => {
+                let unit = self.hex_unit()?;
+                let code = match unit {
+                    0xd800..=0xdbff => {
+                        if !self.input[self.position..].starts_with(b"\\u") {
+                            return Err(self.error("unpaired surrogate"));
+                        }
+                        self.position += 2;
+                        let low = self.hex_unit()?;
+                        if !(0xdc00..=0xdfff).contains(&low) {
+                            return Err(self.error("unpaired surrogate"));
+                        }
+                        0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00)
+                    }
+                    0xdc00..=0xdfff => return Err(self.error("unpaired surrogate")),
+                    unit => unit,
+                };
+                char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))?
+            }
+            _ => {
+                self.position -= 1;
+                return Err(self.error("invalid escape"));
+            }
+        };
+        Ok(c)
+    }
+
+    fn hex_unit(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .text
+            .get(self.position..self.position + 4)
+            .filter(|digits| digits.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.position += 4;
+        u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid unicode escape"))
+    }
+
+    fn number(&mut self) -> Result<Value, JsonError> {
+        let start = self.position;
+        self.eat(b'-');
+
+        // No leading zeros
+        if !self.eat(b'0') && !self.digits() {
+            return Err(self.error("invalid number"));
+        }
+        if self.eat(b'.') && !self.digits() {
+            return Err(self.error("expected digits after '.'"));
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if !self.digits() {
+                return Err(self.error("expected digits in exponent"));
+            }
+        }
+
+        match self.text[start..self.position].parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(Value::Number(number)),
+            _ => {
+                self.position = start;
+                Err(self.error("number out of range"))
+            }
+        }
+    }
+
+    // Consume a run of decimal digits, returning whether there was any
+    fn digits(&mut self) -> bool {
+        let start = self.position;
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.position += 1;
+        }
+        self.position > start
+    }
+}
+
+// Conversion into a JSON value, for response bodies
+pub trait ToJson {
+    fn to_json(&self) -> Value;
+}
+
+// Typed extraction from a JSON value, for request bodies
+//
+// Implement this for request types by pulling members out with `Value::field`,
+// which records where in the document a mismatch occurred.
+pub trait FromJson: Sized {
+    fn from_json(value: &Value) -> Result<Self, JsonError>;
+}
+
+impl ToJson for Value {
+    fn to_json(&self) -> Value {
+        self.clone()
+    }
+}
+
+impl FromJson for Value {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        Ok(value.clone())
+    }
+}
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn to_json(&self) -> Value {
+        (**self).to_json()
+    }
+}
+
+impl ToJson for bool {
+    fn to_json(&self) -> Value {
+        Value::Bool(*self)
+    }
+}
+
+impl FromJson for bool {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        value
+            .as_bool()
+            .ok_or_else(|| JsonError::expected("a boolean", value))
+    }
+}
+
+impl ToJson for str {
+    fn to_json(&self) -> Value {
+        Value::String(self.to_string())
+    }
+}
+
+impl ToJson for String {
+    fn to_json(&self) -> Value {
+        Value::String(self.clone())
+    }
+}
+
+impl FromJson for String {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        value
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| JsonError::expected("a string", value))
+    }
+}
+
+macro_rules! integer_json {
+    ($($integer:ty),*) => {$(
+        impl ToJson for $integer {
+            fn to_json(&self) -> Value {
+                Value::Number(*self as f64)
+            }
+        }
+
+        impl FromJson for $integer {
+            fn from_json(value: &Value) -> Result<Self, JsonError> {
+                let number = value
+                    .as_f64()
+                    .ok_or_else(|| JsonError::expected("an integer", value))?;
+                // MAX rounds up to 2^64 as f64 for u64, so compare against MAX + 1,
+                // which is exact for the small types and still 2^N for the wide ones
+                if number.fract() != 0.0
+                    || number < <$integer>::MIN as f64
+                    || number >= <$integer>::MAX as f64 + 1.0
+                {
+                    return Err(JsonError::Invalid {
+                        path: String::new(),
+                        message: format!("expected an integer in the range of {}", stringify!($integer)),
+                    });
+                }
+                Ok(number as $integer)
+            }
+        }
+    )*};
+}
+
+integer_json!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl ToJson for f64 {
+    fn to_json(&self) -> Value {
+        Value::Number(*self)
+    }
+}
+
+impl FromJson for f64 {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        value
+            .as_f64()
+            .ok_or_else(|| JsonError::expected("a number", value))
+    }
+}
+
+impl ToJson for f32 {
+    fn to_json(&self) -> Value {
+        Value::Number(f64::from(*self))
+    }
+}
+
+impl FromJson for f32 {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        f64::from_json(value).map(|number| number as f32)
+    }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self) -> Value {
+        self.as_ref().map_or(Value::Null, ToJson::to_json)
+    }
+}
+
+// Null and missing members both read as `None`
+impl<T: FromJson> FromJson for Option<T> {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        match value {
+            Value::Null => Ok(None),
+            value => T::from_json(value).map(Some),
+        }
+    }
+}
+
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self) -> Value {
+        Value::Array(self.iter().map(ToJson::to_json).collect())
+    }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self) -> Value {
+        self.as_slice().to_json()
+    }
+}
+
+impl<T: FromJson> FromJson for Vec<T> {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        let values = value
+            .as_array()
+            .ok_or_else(|| JsonError::expected("an array", value))?;
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                T::from_json(value).map_err(|err| err.within(&format!("[{}]", index)))
+            })
+            .collect()
+    }
+}
+
+impl<T: ToJson> ToJson for BTreeMap<String, T> {
+    fn to_json(&self) -> Value {
+        Value::object(
+            self.iter()
+                .map(|(key, value)| (key.as_str(), value.to_json())),
+        )
+    }
+}
+
+impl<T: FromJson> FromJson for BTreeMap<String, T> {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        members(value)
+    }
+}
+
+impl<T: ToJson> ToJson for HashMap<String, T> {
+    fn to_json(&self) -> Value {
+        Value::object(
+            self.iter()
+                .map(|(key, value)| (key.as_str(), value.to_json())),
+        )
+    }
+}
+
+impl<T: FromJson> FromJson for HashMap<String, T> {
+    fn from_json(value: &Value) -> Result<Self, JsonError> {
+        members(value)
+    }
+}
+
+fn members<T, C>(value: &Value) -> Result<C, JsonError>
+where
+    T: FromJson,
+    C: FromIterator<(String, T)>,
+{
+    match value {
+        Value::Object(members) => members
+            .iter()
+            .map(|(key, value)| {
+                T::from_json(value)
+                    .map(|value| (key.clone(), value))
+                    .map_err(|err| err.within(&format!(".{}", key)))
+            })
+            .collect(),
+        _ => Err(JsonError::expected("an object", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_error(input: &str) -> (usize, &'static str) {
+        match parse(input) {
+            Err(JsonError::Syntax { offset, message }) => (offset, message),
+            other => panic!("expected a syntax error for {:?}, got {:?}", input, other),
+        }
+    }
+
+    #[test]
+    fn parses_and_prints_documents() {
+        let text = r#"{"a":[1,2.5,-3e2,true,null],"b":{"c":"d\n"}}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("a").unwrap().as_array().unwrap().len(), 5);
+        assert_eq!(
+            value.get("b").unwrap().get("c").unwrap().as_str(),
+            Some("d\n")
+        );
+        assert_eq!(
+            value.to_string(),
+            r#"{"a":[1,2.5,-300,true,null],"b":{"c":"d\n"}}"#
+        );
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_offsets() {
+        assert_eq!(syntax_error(""), (0, "unexpected end of input"));
+        assert_eq!(syntax_error("[1,]").0, 3);
+        assert_eq!(syntax_error("{\"a\" 1}"), (5, "expected ':' after key"));
+        assert_eq!(syntax_error("{1:2}"), (1, "expected a string key"));
+        assert_eq!(syntax_error("\"abc").1, "unterminated string");
+        assert_eq!(syntax_error("\"a\u{1}\"").1, "control character in string");
+        assert_eq!(syntax_error("\"\\x\"").1, "invalid escape");
+        assert_eq!(syntax_error("01").0, 1);
+        assert_eq!(syntax_error("1 2").0, 2);
+        assert!(matches!(
+            parse_bytes(b"\"\xff\""),
+            Err(JsonError::Syntax {
+                offset: 1,
+                message: "invalid UTF-8"
+            })
+        ));
+    }
+
+    #[test]
+    fn decodes_surrogate_pairs() {
+        let value = parse(r#""\ud83d\ude00 \u00e9""#).unwrap();
+        assert_eq!(value.as_str(), Some("\u{1f600} \u{e9}"));
+        assert_eq!(syntax_error(r#""\ud83d""#).1, "unpaired surrogate");
+        assert_eq!(syntax_error(r#""\ud83d\u0041""#).1, "unpaired surrogate");
+        assert_eq!(syntax_error(r#""\ude00""#).1, "unpaired surrogate");
+        assert_eq!(syntax_error(r#""\u12g4""#).1, "invalid unicode escape");
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(syntax_error(&nested(MAX_DEPTH + 1)).0, MAX_DEPTH);
+        assert!(parse(&"{\"a\":".repeat(100_000)).is_err());
+    }
+
+    #[test]
+    fn last_duplicate_key_wins_in_first_position() {
+        let value = parse(r#"{"a":1,"b":2,"a":3}"#).unwrap();
+        assert_eq!(value.to_string(), r#"{"a":3,"b":2}"#);
+    }
+
+    #[test]
+    fn parses_objects_with_many_keys() {
+        let members: Vec<String> = (0..80_000).map(|i| format!("\"k{}\":{}", i, i)).collect();
+        let text = format!("{{{}}}", members.join(","));
+        let value = parse(&text).unwrap();
+        assert_eq!(value.field::<u32>("k0").unwrap(), 0);
+        assert_eq!(value.field::<u32>("k79999").unwrap(), 79_999);
+    }
+
+    #[test]
+    fn rejects_integers_out_of_range() {
+        let integer = |text: &str| parse(text).unwrap();
+        assert_eq!(u8::from_json(&integer("255")).unwrap(), 255);
+        assert!(u8::from_json(&integer("256")).is_err());
+        assert_eq!(i8::from_json(&integer("-128")).unwrap(), -128);
+        assert!(i8::from_json(&integer("-129")).is_err());
+        assert!(u64::from_json(&integer("18446744073709551616")).is_err());
+        assert!(i64::from_json(&integer("9223372036854775808")).is_err());
+        assert_eq!(
+            i64::from_json(&integer("-9223372036854775808")).unwrap(),
+            i64::MIN
+        );
+        assert!(u32::from_json(&integer("1.5")).is_err());
+    }
+
+    #[test]
+    fn reports_the_path_of_shape_errors() {
+        let value = parse(r#"{"items":[1,"two"]}"#).unwrap();
+        let err = value.field::<Vec<u32>>("items").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "$.items[1]: expected an integer, found a string"
+        );
+    }
+}
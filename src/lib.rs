@@ -0,0 +1,23 @@
+// Building blocks shared by the HTTP server binary
+pub mod brotli;
+pub mod chunked;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod connection;
+pub mod deflate;
+pub mod form;
+pub mod httpdate;
+pub mod inflate;
+pub mod json;
+pub mod middleware;
+pub mod pool;
+pub mod range;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod shutdown;
+pub mod state;
+pub mod static_files;
+pub mod toml;
+pub mod url;
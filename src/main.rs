@@ -1,15 +1,24 @@
-use http::method::Method;
-use httparse::Request;
-use log::error;
-use std::borrow::Cow;
-use std::collections::HashMap;
+use http::{header, Method, StatusCode, Version};
+use log::{debug, error, info, warn};
+use rust_http_server::compression::{self, Compression};
+use rust_http_server::conditional::{self, CachePolicy};
+use rust_http_server::config::{Config, ConfigError, USAGE};
+use rust_http_server::connection::{KeepAlive, Listener, Stream};
+use rust_http_server::middleware::{AccessLog, Next};
+use rust_http_server::pool::WorkerPool;
+use rust_http_server::range;
+use rust_http_server::request::{Limits, ReadError, Request, RequestReader, MAX_HEADERS};
+use rust_http_server::response::Response;
+use rust_http_server::router::{RouteMatch, Router};
+use rust_http_server::shutdown::{self, ConnectionGuard, ConnectionTracker};
+use rust_http_server::static_files::{escape_html, StaticFiles};
 use std::error::Error;
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 
-// Define a function type for handlers
-type HandlerFn = fn(&str) -> (String, &'static str, u16);
+// Pause after a failed accept, so a lasting error does not spin the accept loop
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(50);
 
 // HTML template for generating responses
 const HTML_TEMPLATE: &str = r#"
@@ -24,124 +33,657 @@ const HTML_TEMPLATE: &str = r#"
 </html>
 "#;
 
-// Create a response tuple
-fn create_response(title: &str, content: &str, status_code: u16) -> (String, &'static str, u16) {
+// Create an HTML response from the page template
+fn create_response(title: &str, content: &str, status: StatusCode) -> Response {
     let response_content = HTML_TEMPLATE
         .replace("{title}", title)
         .replace("{content}", content);
 
-    (response_content, "text/html", status_code)
+    Response::html(status, response_content)
 }
 
 // Handlers for specific routes
-fn handle_hello(_: &str) -> (String, &'static str, u16) {
-    create_response("Hello Page", "Hello, Rust HTTP Server!", 200)
+fn handle_hello(_: &Request) -> Response {
+    create_response("Hello Page", "Hello, Rust HTTP Server!", StatusCode::OK)
 }
 
-fn handle_goodbye(_: &str) -> (String, &'static str, u16) {
-    create_response("Goodbye Page", "Goodbye, Rust HTTP Server!", 200)
+fn handle_goodbye(_: &Request) -> Response {
+    create_response("Goodbye Page", "Goodbye, Rust HTTP Server!", StatusCode::OK)
 }
 
-fn handle_submit(_: &str) -> (String, &'static str, u16) {
-    create_response("Submission Page", "Data submitted successfully!", 200)
+fn handle_submit(request: &Request) -> Response {
+    let form = match request.form() {
+        Ok(form) => form,
+        Err(err) => {
+            let status = err.status_code();
+            let title = format!(
+                "{} - {}",
+                status.as_str(),
+                status.canonical_reason().unwrap_or("Error")
+            );
+            return create_response(&title, &escape_html(&err.to_string()), status);
+        }
+    };
+
+    for (name, value) in form.fields.iter() {
+        debug!("Submitted field {}: {} bytes", name, value.len());
+    }
+    for file in &form.files {
+        debug!(
+            "Submitted file {}: {:?}, {} bytes",
+            file.name, file.filename, file.size
+        );
+    }
+
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    let message = format!(
+        "Data submitted successfully! Received {} field{} and {} file{}.",
+        form.fields.len(),
+        plural(form.fields.len()),
+        form.files.len(),
+        plural(form.files.len())
+    );
+    create_response("Submission Page", &message, StatusCode::OK)
+}
+
+fn handle_static(files: &StaticFiles, request: &Request) -> Response {
+    files.serve(request).unwrap_or_else(|status| {
+        let reason = status.canonical_reason().unwrap_or("Error");
+        create_response(&format!("{} - {}", status.as_str(), reason), reason, status)
+    })
+}
+
+fn handle_not_found(_: &Request) -> Response {
+    create_response("404 - Not Found", "Not Found", StatusCode::NOT_FOUND)
+}
+
+fn handle_method_not_allowed(_: &Request) -> Response {
+    create_response(
+        "405 - Method Not Allowed",
+        "Method Not Allowed",
+        StatusCode::METHOD_NOT_ALLOWED,
+    )
 }
 
-fn handle_not_found(_: &str) -> (String, &'static str, u16) {
-    create_response("404 - Not Found", "Not Found", 404)
+// Format a list of methods for an Allow header
+fn allow_header(methods: &[Method]) -> String {
+    methods
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
-// Handle client request
+// Handle client connection, serving requests until it closes or stops being kept alive
+//
+// `guard` marks the connection idle between requests, so it can be closed when
+// other connections are waiting for a worker.
 fn handle_client(
-    mut stream: TcpStream,
-    routes: Arc<HashMap<(&'static str, Method), HandlerFn>>,
+    stream: Stream,
+    guard: &ConnectionGuard,
+    routes: Arc<Router>,
+    limits: Limits,
+    keep_alive: KeepAlive,
+    compression: Compression,
 ) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0; 1024];
-    let read_bytes = stream.read(&mut buffer)?;
+    stream.set_read_timeout(Some(keep_alive.idle_timeout))?;
+    let mut reader = RequestReader::new(stream, limits);
+    let mut served = 0;
 
-    if read_bytes == 0 {
-        return Ok(());
-    }
+    loop {
+        // Pipelined requests are already buffered in the reader and are served in order
+        guard.set_idle(served > 0 && !reader.has_buffered_input());
+        let read = reader.read_request();
+        guard.set_idle(false);
+        let raw_request = match read {
+            Ok(Some(raw_request)) => raw_request,
+            Ok(None) => return Ok(()),
+            Err(ReadError::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                debug!("Closing idle connection after {} requests", served);
+                return Ok(());
+            }
+            Err(err) => {
+                error!("Failed to read request: {}", err);
+                if let Some(status) = err.status_code() {
+                    write_error_response(reader.get_mut(), status, &err.to_string());
+                }
+                return Ok(());
+            }
+        };
 
-    let request = String::from_utf8_lossy(&buffer[..read_bytes]);
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut parsed_request = Request::new(&mut headers);
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed_request = httparse::Request::new(&mut headers);
 
-    if let Err(err) = parsed_request.parse(request.as_bytes()) {
-        error!("Failed to parse request: {}", err);
-        return Ok(());
-    }
+        if let Err(err) = parsed_request.parse(&raw_request.head) {
+            error!("Failed to parse request: {}", err);
+            return Ok(());
+        }
+
+        // Convert the parsed request into the structured form handlers receive
+        let mut request = match Request::from_parsed(&parsed_request, raw_request.body) {
+            Ok(request) => request,
+            Err(err) => {
+                error!("Invalid request: {}", err);
+                let status = err.status_code().unwrap_or(StatusCode::BAD_REQUEST);
+                write_error_response(reader.get_mut(), status, &err.to_string());
+                return Ok(());
+            }
+        };
 
-    // Extract HTTP method and path
-    let http_method =
-        Method::from_bytes(parsed_request.method.unwrap().as_bytes()).expect("Invalid HTTP method");
-    let path = Cow::Borrowed(parsed_request.path.unwrap());
+        request.trailers = raw_request.trailers;
+        if let Err(err) = request.decode_body(limits.max_decompressed_size) {
+            error!("Rejected request body: {}", err);
+            let status = err.status_code().unwrap_or(StatusCode::BAD_REQUEST);
+            write_error_response(reader.get_mut(), status, &err.to_string());
+            return Ok(());
+        }
 
-    // Find and call the appropriate handler for the request
-    let (response_content, content_type, status_code) = find_handler(&path, http_method, &routes)
-        .map_or_else(|| handle_not_found(&request), |handler| handler(&request));
+        served += 1;
+        let mut response = handle_request(&mut request, &routes, compression);
 
-    // Construct and send the response
-    let response = format!(
-        "HTTP/1.1 {}\r\nContent-Length: {}\r\nContent-Type: {}\r\n\r\n{}",
-        status_code,
-        response_content.len(),
-        content_type,
-        response_content
-    );
+        // HTTP/1.0 clients cannot decode chunked bodies, so a stream ends when the connection does
+        let http_10 = request.version == Version::HTTP_10;
+        let send_body = request.method != Method::HEAD;
+        let close_delimited = http_10 && send_body && response.body.len().is_none();
+        let persist = request.wants_keep_alive()
+            && served < keep_alive.max_requests
+            && !shutdown::is_requested()
+            && !close_delimited;
+        if !persist {
+            response = response.header(header::CONNECTION, "close");
+        } else if http_10 {
+            response = response.header(header::CONNECTION, "keep-alive");
+        }
 
-    if let Err(err) = stream.write_all(response.as_bytes()) {
-        error!("Failed to write response: {}", err);
+        let written = if http_10 {
+            response.write_unchunked_to(reader.get_mut(), send_body)
+        } else if send_body {
+            response.write_to(reader.get_mut())
+        } else {
+            response.write_head_to(reader.get_mut())
+        };
+
+        if let Err(err) = written {
+            error!("Failed to write response: {}", err);
+            return Ok(());
+        }
+
+        if !persist {
+            return Ok(());
+        }
     }
+}
 
-    if let Err(err) = stream.flush() {
-        error!("Failed to flush stream: {}", err);
+// Find and call the appropriate handler for the request, running it through the middleware
+//
+// `compression` applies to routes that do not override it.
+fn handle_request(request: &mut Request, routes: &Router, compression: Compression) -> Response {
+    request.app_state = Arc::clone(routes.app_state());
+    let route = routes.find(&request.method, &request.path);
+    let mut chain = routes.global_middleware().to_vec();
+    match &route {
+        RouteMatch::Found {
+            params, middleware, ..
+        } => {
+            request.params = params.clone();
+            chain.extend(middleware.iter().cloned());
+        }
+        RouteMatch::MethodNotAllowed { middleware, .. }
+        | RouteMatch::Options { middleware, .. } => chain.extend(middleware.iter().cloned()),
+        RouteMatch::NotFound => {}
     }
+    Next::new(&chain, &|request: &mut Request| {
+        dispatch(request, &route, compression)
+    })
+    .run(request)
+}
 
-    Ok(())
+// Produce the response for a route match, innermost in the middleware chain
+//
+// Compression, conditional requests and ranges shape the handler's response
+// here, so middleware sees the final status such as 304 or 206.
+fn dispatch(request: &mut Request, route: &RouteMatch, compression: Compression) -> Response {
+    match route {
+        RouteMatch::Found {
+            handler,
+            cache_policy,
+            compression: route_compression,
+            ..
+        } => {
+            let response = handler.call(request);
+            let response =
+                compression::apply(request, response, route_compression.unwrap_or(compression));
+            let response = conditional::apply(request, response, cache_policy.as_ref());
+            range::apply(request, response)
+        }
+        RouteMatch::MethodNotAllowed { allowed, .. } => {
+            handle_method_not_allowed(request).header(header::ALLOW, allow_header(allowed))
+        }
+        RouteMatch::Options { allowed, .. } => {
+            Response::new(StatusCode::NO_CONTENT).header(header::ALLOW, allow_header(allowed))
+        }
+        RouteMatch::NotFound => handle_not_found(request),
+    }
 }
 
-// Find the appropriate handler for a given route and HTTP method
-fn find_handler<'a>(
-    path: &str,
-    method: Method,
-    routes: &HashMap<(&'static str, Method), HandlerFn>,
-) -> Option<HandlerFn> {
-    routes.get(&(path, method)).copied()
+// Send a response and flush the stream
+fn write_response(stream: &mut Stream, response: Response) {
+    if let Err(err) = response.write_to(stream) {
+        error!("Failed to write response: {}", err);
+    }
+}
+
+// Send an error page for a request that could not be read or parsed, closing the connection
+fn write_error_response(stream: &mut Stream, status: StatusCode, message: &str) {
+    let title = format!(
+        "{} - {}",
+        status.as_str(),
+        status.canonical_reason().unwrap_or("Error")
+    );
+    let mut response = create_response(&title, message, status).header(header::CONNECTION, "close");
+    // A 415 from the request reader is always about Content-Encoding; say which ones work
+    if status == StatusCode::UNSUPPORTED_MEDIA_TYPE {
+        response = response.header(header::ACCEPT_ENCODING, "gzip, deflate");
+    }
+    write_response(stream, response);
+}
+
+// Answer a connection the worker pool has no room for with a 503
+fn reject_busy(mut stream: Stream) {
+    warn!("Worker queue full, rejecting connection");
+
+    // Never let a slow client stall the accept loop
+    if let Err(err) = stream.set_write_timeout(Some(Duration::from_secs(1))) {
+        error!("Failed to set write timeout: {}", err);
+        return;
+    }
+
+    let response = create_response(
+        "503 - Service Unavailable",
+        "Server is busy, please retry shortly",
+        StatusCode::SERVICE_UNAVAILABLE,
+    )
+    .header(header::RETRY_AFTER, "1")
+    .header(header::CONNECTION, "close");
+    write_response(&mut stream, response);
 }
 
 // Main entry point of the application
 fn main() -> Result<(), Box<dyn Error>> {
-    // Initialize the logger
-    env_logger::init();
+    // Load settings from defaults, the config file, the environment and flags
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(ConfigError::HelpRequested) => {
+            print!("{}", USAGE);
+            return Ok(());
+        }
+        Err(err) => {
+            eprintln!("Configuration error: {}", err);
+            eprintln!("Run with --help for usage");
+            std::process::exit(2);
+        }
+    };
+
+    // Initialize the logger, letting RUST_LOG refine the configured level
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .parse_default_env()
+        .init();
 
-    // Bind to the listener address
-    let listener = TcpListener::bind("127.0.0.1:8080")?;
+    // Bind to every configured address
+    let mut listeners = Vec::with_capacity(config.listen.len());
+    for address in &config.listen {
+        let listener = Listener::bind(address)
+            .map_err(|err| format!("Failed to bind {}: {}", address, err))?;
+        // Several listeners share one accept loop, so none may block it
+        listener.set_nonblocking(true)?;
+        info!("Listening on {}", listener.local_addr()?);
+        listeners.push(listener);
+    }
 
     // Create the routes and handlers
-    let routes: Arc<HashMap<(&str, Method), HandlerFn>> = Arc::new({
-        let mut routes: HashMap<(&str, Method), HandlerFn> = HashMap::new();
-        routes.insert(("/hello", Method::GET), handle_hello);
-        routes.insert(("/bye", Method::GET), handle_goodbye);
-        routes.insert(("/submit", Method::POST), handle_submit);
+    let routes = Arc::new({
+        let mut routes = Router::new();
+        // Lets form parsing check its limits against the body size limit
+        routes.state(config.limits());
+        routes.middleware(AccessLog);
+        routes.get("/hello", handle_hello);
+        routes.cache_policy("/hello", CachePolicy::NoCache);
+        routes.get("/bye", handle_goodbye);
+        routes.post("/submit", handle_submit);
+
+        if let Some(dir) = &config.static_dir {
+            let files = StaticFiles::new(dir)
+                .map_err(|err| format!("Cannot serve {}: {}", dir.display(), err))?
+                .directory_listing(config.directory_listing);
+            info!(
+                "Serving {} under {}/",
+                files.root().display(),
+                config.static_prefix
+            );
+            let pattern = format!("{}/*path", config.static_prefix);
+            routes.get(&pattern, move |request: &Request| {
+                handle_static(&files, request)
+            });
+            routes.cache_policy(
+                &pattern,
+                CachePolicy::Public {
+                    max_age: config.static_max_age,
+                },
+            );
+        }
+
         routes
     });
+    for route in routes.routes() {
+        debug!("Route {}", route);
+    }
 
-    // Listen for incoming connections and handle them concurrently
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let routes = routes.clone();
-                std::thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, routes) {
-                        error!("Error handling client: {}", e);
-                    }
-                });
+    shutdown::install_signal_handlers()?;
+    let tracker = ConnectionTracker::new();
+
+    // Hand connections to a fixed pool of workers, shedding load when the queue is full
+    let limits = config.limits();
+    let keep_alive = config.keep_alive();
+    let compression = config.compression();
+    let pool = WorkerPool::new(
+        config.workers,
+        config.queue_depth,
+        move |(stream, guard): (Stream, ConnectionGuard)| {
+            let routes = routes.clone();
+            if let Err(e) = handle_client(stream, &guard, routes, limits, keep_alive, compression) {
+                error!("Error handling client: {}", e);
             }
-            Err(e) => {
-                error!("Error accepting connection: {}", e);
+        },
+    );
+
+    while !shutdown::is_requested() {
+        // Sleeps until a connection arrives or a shutdown signal wakes it
+        shutdown::wait_for_connection(&listeners)?;
+        let mut failed = false;
+
+        for listener in &listeners {
+            match listener.accept() {
+                Ok(stream) => {
+                    let guard = match tracker.register(&stream) {
+                        Ok(guard) => guard,
+                        Err(e) => {
+                            error!("Failed to track connection: {}", e);
+                            continue;
+                        }
+                    };
+                    let queued = pool.try_execute((stream, guard));
+                    // With every worker busy, idle keep-alive connections give way to new ones
+                    if queued.is_err() || pool.queued() > 0 {
+                        let closed = tracker.close_idle();
+                        if closed > 0 {
+                            debug!("Closed {} idle connections to free workers", closed);
+                        }
+                    }
+                    if let Err((stream, _)) = queued {
+                        reject_busy(stream);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
+                    failed = true;
+                }
             }
         }
+
+        // Errors such as running out of file descriptors persist for a while
+        if failed {
+            std::thread::sleep(ACCEPT_ERROR_BACKOFF);
+        }
     }
 
+    // Stop accepting, then give in-flight connections until the deadline to finish
+    drop(listeners);
+    info!(
+        "Shutting down, draining {} connections",
+        tracker.active_count()
+    );
+    let report = tracker.drain(config.shutdown_timeout);
+    pool.join();
+    info!(
+        "Shutdown complete: {} connections drained, {} aborted",
+        report.drained, report.aborted
+    );
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_http_server::response::Body;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn routes() -> Router {
+        let mut router = Router::new();
+        router.get("/hello", |_: &Request| {
+            Response::text(StatusCode::OK, "hello")
+        });
+        router.post("/hello", |_: &Request| {
+            Response::text(StatusCode::OK, "posted")
+        });
+        router
+    }
+
+    // Open a connection served by `handle_client` on another thread
+    fn connect(routes: Router, keep_alive: KeepAlive) -> (TcpStream, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = Stream::Tcp(listener.accept().unwrap().0);
+
+        let tracker = ConnectionTracker::new();
+        let guard = tracker.register(&stream).unwrap();
+        let routes = Arc::new(routes);
+        let server = thread::spawn(move || {
+            handle_client(
+                stream,
+                &guard,
+                routes,
+                Limits::default(),
+                keep_alive,
+                Compression::default(),
+            )
+            .unwrap();
+        });
+        (client, server)
+    }
+
+    // Write raw requests, end the client's side and read all the server sends back
+    fn exchange(routes: Router, keep_alive: KeepAlive, requests: &str) -> String {
+        let (mut client, server) = connect(routes, keep_alive);
+        client.write_all(requests.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        server.join().unwrap();
+        output
+    }
+
+    #[test]
+    fn answers_wrong_methods_with_405_and_allow() {
+        let output = exchange(
+            routes(),
+            KeepAlive::default(),
+            "DELETE /hello HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("\r\nallow: GET, HEAD, OPTIONS, POST\r\n"),
+            "{}",
+            output
+        );
+
+        let output = exchange(
+            routes(),
+            KeepAlive::default(),
+            "GET /missing HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{}",
+            output
+        );
+        assert!(!output.contains("allow:"), "{}", output);
+    }
+
+    #[test]
+    fn answers_head_with_the_get_headers_only() {
+        let output = exchange(
+            routes(),
+            KeepAlive::default(),
+            "HEAD /hello HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.contains("\r\nContent-Length: 5\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\n"), "{}", output);
+    }
+
+    #[test]
+    fn answers_options_for_paths_and_the_server() {
+        let output = exchange(
+            routes(),
+            KeepAlive::default(),
+            "OPTIONS /hello HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 204 No Content\r\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("\r\nallow: GET, HEAD, OPTIONS, POST\r\n"),
+            "{}",
+            output
+        );
+        assert!(!output.contains("Content-Length"), "{}", output);
+
+        let mut router = routes();
+        router.delete("/items/:id", |_: &Request| {
+            Response::new(StatusCode::NO_CONTENT)
+        });
+        let output = exchange(
+            router,
+            KeepAlive::default(),
+            "OPTIONS * HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+        );
+        assert!(
+            output.starts_with("HTTP/1.1 204 No Content\r\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("\r\nallow: DELETE, GET, HEAD, OPTIONS, POST\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn answers_pipelined_requests_in_order_on_one_connection() {
+        let output = exchange(
+            routes(),
+            KeepAlive::default(),
+            "GET /hello HTTP/1.1\r\nHost: test\r\n\r\n\
+             POST /hello HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n\r\n\
+             GET /hello HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n\
+             GET /hello HTTP/1.1\r\nHost: test\r\n\r\n",
+        );
+        let responses: Vec<&str> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", output);
+        assert!(responses[0].ends_with("\r\n\r\nhello"), "{}", output);
+        assert!(responses[1].ends_with("\r\n\r\nposted"), "{}", output);
+        assert!(responses[2].ends_with("\r\n\r\nhello"), "{}", output);
+        assert!(!responses[0].contains("connection:"), "{}", output);
+        assert!(
+            responses[2].contains("\r\nconnection: close\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn closes_http_1_0_connections_unless_asked_to_keep_them() {
+        let request = "GET /hello HTTP/1.0\r\n\r\n";
+        let output = exchange(routes(), KeepAlive::default(), &request.repeat(2));
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1, "{}", output);
+        assert!(output.contains("\r\nconnection: close\r\n"), "{}", output);
+
+        let request = "GET /hello HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+        let output = exchange(routes(), KeepAlive::default(), &request.repeat(2));
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 2, "{}", output);
+        assert_eq!(output.matches("\r\nconnection: keep-alive\r\n").count(), 2);
+    }
+
+    #[test]
+    fn closes_http_1_0_streams_instead_of_chunking() {
+        let mut router = routes();
+        router.get("/stream", |_: &Request| {
+            Response::new(StatusCode::OK).body(Body::from_stream(&b"streamed"[..]))
+        });
+        let output = exchange(
+            router,
+            KeepAlive::default(),
+            "GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+             GET /hello HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        );
+        assert!(output.contains("\r\nconnection: close\r\n"), "{}", output);
+        assert!(!output.contains("Transfer-Encoding"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nstreamed"), "{}", output);
+    }
+
+    #[test]
+    fn closes_after_max_requests() {
+        let keep_alive = KeepAlive {
+            max_requests: 2,
+            ..KeepAlive::default()
+        };
+        let request = "GET /hello HTTP/1.1\r\nHost: test\r\n\r\n";
+        let output = exchange(routes(), keep_alive, &request.repeat(3));
+        let responses: Vec<&str> = output.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(responses.len(), 2, "{}", output);
+        assert!(!responses[0].contains("connection:"), "{}", output);
+        assert!(
+            responses[1].contains("\r\nconnection: close\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn closes_connections_idle_past_the_timeout() {
+        let keep_alive = KeepAlive {
+            idle_timeout: Duration::from_millis(50),
+            ..KeepAlive::default()
+        };
+        let (mut client, server) = connect(routes(), keep_alive);
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+        // The client never ends its side; the server hangs up once the timeout passes
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        server.join().unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(!output.contains("connection: close"), "{}", output);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::request::Request;
+use crate::response::Response;
+use log::info;
+use std::sync::Arc;
+use std::time::Instant;
+
+// Code that runs around route dispatch
+//
+// A middleware sees the request before the handler and may change it, answer
+// it itself by returning without calling `next`, or post-process the response
+// that `next.run` returns. Closures of the same shape are middleware too.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+
+    // Name shown in the route table
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next<'_>) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+// The rest of the chain: the remaining middleware followed by the endpoint
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&mut Request) -> Response,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(
+        middleware: &'a [Arc<dyn Middleware>],
+        endpoint: &'a dyn Fn(&mut Request) -> Response,
+    ) -> Self {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    // Pass the request on and return the response produced further in
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+// Logs every request with its status and the time taken to produce the response
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let started = Instant::now();
+        let method = request.method.clone();
+        let path = request.path.clone();
+        let response = next.run(request);
+        info!(
+            "{} {} {} {:.1?}",
+            method,
+            path,
+            response.status.as_u16(),
+            started.elapsed()
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Body;
+    use http::{HeaderValue, StatusCode};
+    use std::sync::Mutex;
+
+    // Middleware that records when it runs, before and after the rest of the chain
+    fn tracer(label: &'static str, trace: &Arc<Mutex<Vec<String>>>) -> Arc<dyn Middleware> {
+        let trace = trace.clone();
+        Arc::new(move |request: &mut Request, next: Next<'_>| {
+            trace.lock().unwrap().push(format!("{} in", label));
+            let response = next.run(request);
+            trace.lock().unwrap().push(format!("{} out", label));
+            response
+        })
+    }
+
+    #[test]
+    fn runs_middleware_in_order_around_the_endpoint() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let chain = [tracer("outer", &trace), tracer("inner", &trace)];
+        let endpoint_trace = trace.clone();
+        let endpoint = move |_: &mut Request| {
+            endpoint_trace.lock().unwrap().push("endpoint".to_string());
+            Response::new(StatusCode::OK)
+        };
+
+        let mut request = Request::for_test("GET", "/", &[], b"");
+        let response = Next::new(&chain, &endpoint).run(&mut request);
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(
+            *trace.lock().unwrap(),
+            ["outer in", "inner in", "endpoint", "inner out", "outer out"]
+        );
+    }
+
+    #[test]
+    fn passes_changes_in_and_out_of_the_chain() {
+        let chain: [Arc<dyn Middleware>; 1] =
+            [Arc::new(|request: &mut Request, next: Next<'_>| {
+                request
+                    .headers
+                    .insert("x-user", HeaderValue::from_static("alice"));
+                next.run(request).header("x-served-by", "chain")
+            })];
+        let endpoint = |request: &mut Request| {
+            Response::text(StatusCode::OK, request.header("x-user").unwrap_or("nobody"))
+        };
+
+        let mut request = Request::for_test("GET", "/", &[], b"");
+        let response = Next::new(&chain, &endpoint).run(&mut request);
+        assert_eq!(response.headers["x-served-by"], "chain");
+        assert!(matches!(response.body, Body::Bytes(ref body) if body == b"alice"));
+    }
+
+    #[test]
+    fn short_circuits_without_calling_next() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let chain = [
+            tracer("outer", &trace),
+            Arc::new(|request: &mut Request, next: Next<'_>| {
+                if request.header("authorization").is_none() {
+                    return Response::new(StatusCode::UNAUTHORIZED);
+                }
+                next.run(request)
+            }) as Arc<dyn Middleware>,
+            tracer("inner", &trace),
+        ];
+        let endpoint = |_: &mut Request| -> Response { panic!("endpoint must not run") };
+
+        let mut request = Request::for_test("GET", "/", &[], b"");
+        let response = Next::new(&chain, &endpoint).run(&mut request);
+        assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(*trace.lock().unwrap(), ["outer in", "outer out"]);
+    }
+}
@@ -0,0 +1,172 @@
+use log::{debug, error};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Fixed-size pool of worker threads fed from a bounded queue
+//
+// Every job is an item of type `T` (an accepted connection, for instance) passed to
+// the same handler. When the queue is full the item is handed back to the caller so
+// it can shed the load itself.
+pub struct WorkerPool<T> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+    // Items queued but not yet taken by a worker
+    queued: Arc<AtomicUsize>,
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(worker_count: usize, queue_depth: usize, handler: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(worker_count > 0, "worker pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..worker_count)
+            .map(|id| {
+                let receiver = receiver.clone();
+                let handler = handler.clone();
+                let queued = queued.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{}", id))
+                    .spawn(move || run_worker(id, receiver, handler, queued))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool {
+            sender: Some(sender),
+            workers,
+            queued,
+        }
+    }
+
+    // Queue an item for a worker, returning it if the queue is full
+    pub fn try_execute(&self, item: T) -> Result<(), T> {
+        let sender = self.sender.as_ref().expect("worker pool already shut down");
+        // Counted before sending so a worker taking it at once never sees the count underflow
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                Err(item)
+            }
+        }
+    }
+
+    // Number of items waiting for a free worker
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+impl<T> WorkerPool<T> {
+    // Stop accepting jobs, let workers drain the queue and wait for them to exit
+    pub fn join(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        // Closing the channel makes each worker exit once the queue is empty
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("Worker thread exited abnormally");
+            }
+        }
+    }
+}
+
+impl<T> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_worker<T, F>(
+    id: usize,
+    receiver: Arc<Mutex<Receiver<T>>>,
+    handler: Arc<F>,
+    queued: Arc<AtomicUsize>,
+) where
+    F: Fn(T),
+{
+    loop {
+        let item = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => break,
+        };
+
+        match item {
+            // A panicking handler must not take the worker down with it
+            Ok(item) => {
+                queued.fetch_sub(1, Ordering::SeqCst);
+                if panic::catch_unwind(AssertUnwindSafe(|| handler(item))).is_err() {
+                    error!("Worker {} recovered from a panicking job", id);
+                }
+            }
+            Err(_) => break,
+        }
+    }
+
+    debug!("Worker {} exiting", id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn hands_back_items_when_the_queue_is_full() {
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let started_tx = Mutex::new(started_tx);
+        let release_rx = Mutex::new(release_rx);
+        let pool = WorkerPool::new(1, 1, move |item: u32| {
+            started_tx.lock().unwrap().send(item).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+        });
+
+        // The only worker holds the first item, the queue holds the second
+        assert_eq!(pool.try_execute(1), Ok(()));
+        assert_eq!(started.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(pool.try_execute(2), Ok(()));
+        assert_eq!(pool.queued(), 1);
+        assert_eq!(pool.try_execute(3), Err(3));
+        assert_eq!(pool.queued(), 1);
+
+        release.send(()).unwrap();
+        assert_eq!(started.recv_timeout(Duration::from_secs(5)), Ok(2));
+        assert_eq!(pool.queued(), 0);
+        release.send(()).unwrap();
+        pool.join();
+    }
+
+    #[test]
+    fn workers_survive_panicking_jobs() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let pool = WorkerPool::new(1, 8, move |item: u32| {
+            if item.is_multiple_of(2) {
+                panic!("job {} failed", item);
+            }
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        for item in 0..6 {
+            assert_eq!(pool.try_execute(item), Ok(()));
+        }
+        // Joining drains the queue, so every odd job has run on the one worker
+        pool.join();
+        assert_eq!(handled.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,497 @@
+use crate::httpdate;
+use crate::request::Request;
+use crate::response::{Body, Response};
+use http::header::{self, HeaderValue};
+use http::{Method, StatusCode};
+use log::error;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+// Most ranges honoured in one request; longer lists are answered with the full representation
+const MAX_RANGES: usize = 16;
+
+// An inclusive byte range within a representation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        if self.is_empty() {
+            0
+        } else {
+            self.end - self.start + 1
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.end < self.start
+    }
+
+    // Content-Range value for this range of a representation of `total` bytes
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+// Outcome of parsing a Range header against a representation's length
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ranges {
+    // Malformed, not in bytes, or not worth honouring; send the whole representation
+    Ignored,
+    // Well-formed, but no range overlaps the representation
+    Unsatisfiable,
+    // The satisfiable ranges, in the order requested
+    Satisfiable(Vec<ByteRange>),
+}
+
+// Parse a `bytes=` Range header such as `bytes=0-499, 1000-, -200`
+pub fn parse(value: &str, length: u64) -> Ranges {
+    let specs = match value.trim().split_once('=') {
+        Some((unit, specs)) if unit.trim().eq_ignore_ascii_case("bytes") => specs,
+        _ => return Ranges::Ignored,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Ranges::Ignored,
+        };
+
+        let range = match (parse_position(first), parse_position(last)) {
+            // `-n` asks for the final n bytes
+            (None, Some(suffix)) if first.is_empty() => {
+                if suffix == 0 || length == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: length.saturating_sub(suffix),
+                    end: length - 1,
+                }
+            }
+            (Some(start), end) if last.is_empty() || end.is_some() => {
+                if end.is_some_and(|end| end < start) {
+                    return Ranges::Ignored;
+                }
+                if start >= length {
+                    continue;
+                }
+                ByteRange {
+                    start,
+                    end: end.map_or(length - 1, |end| end.min(length - 1)),
+                }
+            }
+            _ => return Ranges::Ignored,
+        };
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    // Many or overlapping ranges cost more to serve than the whole thing
+    let requested: u64 = ranges.iter().map(ByteRange::len).sum();
+    if ranges.len() > MAX_RANGES || requested > length {
+        return Ranges::Ignored;
+    }
+
+    Ranges::Satisfiable(ranges)
+}
+
+fn parse_position(text: &str) -> Option<u64> {
+    let text = text.trim();
+    if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+// Answer a Range request from a full 200 response with 206 or 416
+//
+// Only in-memory and file bodies can be sliced; streamed bodies are always
+// sent whole. Call after `conditional::apply` so that preconditions win.
+pub fn apply(request: &Request, mut response: Response) -> Response {
+    if response.status != StatusCode::OK {
+        return response;
+    }
+    if !matches!(response.body, Body::Bytes(_) | Body::File { .. }) {
+        return response;
+    }
+    if !response.headers.contains_key(header::ACCEPT_RANGES) {
+        response
+            .headers
+            .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    }
+
+    // Range is only defined for GET
+    if request.method != Method::GET {
+        return response;
+    }
+    let value = match request.header("range") {
+        Some(value) => value,
+        None => return response,
+    };
+    if let Some(if_range) = request.header("if-range") {
+        if !if_range_matches(if_range, &response) {
+            return response;
+        }
+    }
+
+    let length = response.body.len().unwrap_or(0);
+    match parse(value, length) {
+        Ranges::Ignored => response,
+        Ranges::Unsatisfiable => Response::new(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", length)),
+        Ranges::Satisfiable(ranges) => match partial(response, &ranges, length) {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Failed to serve byte ranges: {}", err);
+                Response::new(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        },
+    }
+}
+
+// Whether the If-Range validator still identifies the response, so the range may be sent
+fn if_range_matches(if_range: &str, response: &Response) -> bool {
+    let if_range = if_range.trim();
+    let current = |name| {
+        response
+            .headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+    };
+
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        // Weak tags never match; If-Range needs byte-for-byte identity
+        return !if_range.starts_with("W/")
+            && current(header::ETAG).is_some_and(|etag| etag == if_range);
+    }
+
+    match (
+        httpdate::parse(if_range),
+        current(header::LAST_MODIFIED).and_then(httpdate::parse),
+    ) {
+        (Some(date), Some(modified)) => date == modified,
+        _ => false,
+    }
+}
+
+// Build the 206 response for one or more satisfiable ranges
+fn partial(response: Response, ranges: &[ByteRange], length: u64) -> io::Result<Response> {
+    let Response { headers, body, .. } = response;
+    let mut partial = Response::new(StatusCode::PARTIAL_CONTENT);
+    partial.headers = headers;
+
+    if let [range] = ranges {
+        partial.body = match body {
+            Body::Bytes(bytes) => {
+                Body::Bytes(bytes[range.start as usize..=range.end as usize].to_vec())
+            }
+            Body::File { mut file, .. } => {
+                let origin = file.stream_position()?;
+                file.seek(SeekFrom::Start(origin + range.start))?;
+                Body::File {
+                    file,
+                    length: range.len(),
+                }
+            }
+            other => other,
+        };
+        return Ok(partial.header(header::CONTENT_RANGE, range.content_range(length)));
+    }
+
+    let content_type = partial.headers.remove(header::CONTENT_TYPE);
+    let boundary = boundary();
+    let mut parts = VecDeque::with_capacity(ranges.len() * 2 + 1);
+    let mut total = 0;
+
+    for (index, range) in ranges.iter().enumerate() {
+        let mut head = String::new();
+        if index > 0 {
+            head.push_str("\r\n");
+        }
+        head.push_str(&format!("--{}\r\n", boundary));
+        if let Some(content_type) = content_type.as_ref().and_then(|value| value.to_str().ok()) {
+            head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        head.push_str(&format!(
+            "Content-Range: {}\r\n\r\n",
+            range.content_range(length)
+        ));
+
+        total += head.len() as u64 + range.len();
+        parts.push_back(Part::Literal(Cursor::new(head.into_bytes())));
+        parts.push_back(Part::Range {
+            start: range.start,
+            remaining: range.len(),
+            positioned: false,
+        });
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    total += tail.len() as u64;
+    parts.push_back(Part::Literal(Cursor::new(tail.into_bytes())));
+
+    partial.body = match body {
+        Body::Bytes(bytes) => {
+            Body::from_reader(Multipart::new(Cursor::new(bytes), 0, parts), total)
+        }
+        Body::File { mut file, .. } => {
+            let origin = file.stream_position()?;
+            Body::from_reader(Multipart::new(file, origin, parts), total)
+        }
+        other => other,
+    };
+
+    Ok(partial.header(
+        header::CONTENT_TYPE,
+        format!("multipart/byteranges; boundary={}", boundary),
+    ))
+}
+
+// A boundary that will not plausibly occur inside the payload
+fn boundary() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or(0),
+    );
+    format!("byteranges-{:016x}", hasher.finish())
+}
+
+enum Part {
+    Literal(Cursor<Vec<u8>>),
+    Range {
+        start: u64,
+        remaining: u64,
+        positioned: bool,
+    },
+}
+
+// Streams a multipart/byteranges body, seeking the source to each range in turn
+struct Multipart<S> {
+    source: S,
+    origin: u64,
+    parts: VecDeque<Part>,
+}
+
+impl<S: Read + Seek> Multipart<S> {
+    fn new(source: S, origin: u64, parts: VecDeque<Part>) -> Self {
+        Multipart {
+            source,
+            origin,
+            parts,
+        }
+    }
+}
+
+impl<S: Read + Seek> Read for Multipart<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            match part {
+                Part::Literal(cursor) => {
+                    let read = cursor.read(buf)?;
+                    if read > 0 {
+                        return Ok(read);
+                    }
+                }
+                Part::Range {
+                    start,
+                    remaining,
+                    positioned,
+                } if *remaining > 0 => {
+                    if !*positioned {
+                        self.source.seek(SeekFrom::Start(self.origin + *start))?;
+                        *positioned = true;
+                    }
+                    let limit = buf.len().min(*remaining as usize);
+                    let read = self.source.read(&mut buf[..limit])?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "range extends past the end of the source",
+                        ));
+                    }
+                    *remaining -= read as u64;
+                    return Ok(read);
+                }
+                Part::Range { .. } => {}
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"0123456789abcdefghij";
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        Request::for_test(method, "/file", headers, b"")
+    }
+
+    fn full() -> Response {
+        Response::new(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .header(header::ETAG, "\"v1\"")
+            .header(header::LAST_MODIFIED, "Sun, 06 Nov 1994 08:49:37 GMT")
+            .body(TEXT.to_vec())
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        let length = response.body.len();
+        let mut data = Vec::new();
+        match response.body {
+            Body::Bytes(bytes) => data = bytes,
+            Body::Reader { mut reader, .. } => {
+                reader.read_to_end(&mut data).unwrap();
+            }
+            _ => panic!("unexpected body type"),
+        }
+        assert_eq!(length, Some(data.len() as u64));
+        data
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(
+            parse("bytes=0-4, 10-", 20),
+            Ranges::Satisfiable(vec![range(0, 4), range(10, 19)])
+        );
+        assert_eq!(
+            parse("Bytes = 5-100", 20),
+            Ranges::Satisfiable(vec![range(5, 19)])
+        );
+        assert_eq!(parse("items=0-4", 20), Ranges::Ignored);
+        assert_eq!(parse("bytes=4-2", 20), Ranges::Ignored);
+        assert_eq!(parse("bytes=a-b", 20), Ranges::Ignored);
+        assert_eq!(parse("bytes=+1-2", 20), Ranges::Ignored);
+        assert_eq!(parse("bytes=5", 20), Ranges::Ignored);
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(
+            parse("bytes=-5", 20),
+            Ranges::Satisfiable(vec![range(15, 19)])
+        );
+        // A suffix longer than the representation covers all of it
+        assert_eq!(
+            parse("bytes=-500", 20),
+            Ranges::Satisfiable(vec![range(0, 19)])
+        );
+        assert_eq!(parse("bytes=-0", 20), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn ignores_excessive_ranges() {
+        let many: Vec<String> = (0..=MAX_RANGES).map(|i| format!("{}-{}", i, i)).collect();
+        assert_eq!(
+            parse(&format!("bytes={}", many.join(",")), 100),
+            Ranges::Ignored
+        );
+        assert_eq!(parse("bytes=0-15,5-19", 20), Ranges::Ignored);
+    }
+
+    #[test]
+    fn serves_a_single_range() {
+        let request = request("GET", &[("Range", "bytes=-4")]);
+        let response = apply(&request, full());
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers[header::CONTENT_RANGE], "bytes 16-19/20");
+        assert_eq!(body(response), b"ghij");
+    }
+
+    #[test]
+    fn serves_multiple_ranges_as_multipart() {
+        let request = request("GET", &[("Range", "bytes=0-1,-2")]);
+        let response = apply(&request, full());
+        assert_eq!(response.status, StatusCode::PARTIAL_CONTENT);
+        let content_type = response.headers[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/20\r\n\r\n01\r\n\
+             --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 18-19/20\r\n\r\nij\r\n\
+             --{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(String::from_utf8(body(response)).unwrap(), expected);
+    }
+
+    #[test]
+    fn answers_unsatisfiable_ranges_with_416() {
+        let request = request("GET", &[("Range", "bytes=20-30")]);
+        let response = apply(&request, full());
+        assert_eq!(response.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers[header::CONTENT_RANGE], "bytes */20");
+    }
+
+    #[test]
+    fn sends_the_full_body_unless_if_range_matches() {
+        let cases = [
+            ("\"v1\"", StatusCode::PARTIAL_CONTENT),
+            ("\"v2\"", StatusCode::OK),
+            ("W/\"v1\"", StatusCode::OK),
+            ("Sun, 06 Nov 1994 08:49:37 GMT", StatusCode::PARTIAL_CONTENT),
+            ("Sun, 06 Nov 1994 08:49:38 GMT", StatusCode::OK),
+            ("garbage", StatusCode::OK),
+        ];
+        for (if_range, status) in cases {
+            let request = request("GET", &[("Range", "bytes=0-1"), ("If-Range", if_range)]);
+            assert_eq!(apply(&request, full()).status, status, "{}", if_range);
+        }
+    }
+
+    #[test]
+    fn only_applies_to_get() {
+        let head = request("HEAD", &[("Range", "bytes=0-1")]);
+        let response = apply(&head, full());
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers[header::ACCEPT_RANGES], "bytes");
+    }
+
+    #[test]
+    fn slices_files_from_their_position() {
+        let path = std::env::temp_dir().join(format!("range-test-{}", std::process::id()));
+        std::fs::write(&path, TEXT).unwrap();
+        let mut file = std::fs::File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(10)).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let response = Response::new(StatusCode::OK).body(Body::from_file(file).unwrap());
+        let request = request("GET", &[("Range", "bytes=2-4")]);
+        let response = apply(&request, response);
+        assert_eq!(response.headers[header::CONTENT_RANGE], "bytes 2-4/10");
+        match response.body {
+            Body::File { mut file, length } => {
+                let mut data = vec![0; length as usize];
+                file.read_exact(&mut data).unwrap();
+                assert_eq!(data, b"cde");
+            }
+            _ => panic!("expected a file body"),
+        }
+    }
+}
@@ -0,0 +1,779 @@
+use crate::chunked::{parse_chunk_size, MAX_CHUNK_LINE};
+use crate::deflate::Format;
+use crate::form::{self, Form, FormError, FormOptions};
+use crate::inflate::{self, InflateError};
+use crate::json::{self, FromJson, JsonError};
+use crate::router::Params;
+use crate::state::AppState;
+use crate::url::{percent_decode, QueryParams};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{Method, StatusCode, Version};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Maximum number of headers accepted in a single request
+pub const MAX_HEADERS: usize = 64;
+
+// Size of each read from the underlying stream
+const READ_CHUNK_SIZE: usize = 4096;
+
+// Limits applied while reading a request
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+    // Largest body after undoing its Content-Encoding, which guards against zip bombs
+    pub max_decompressed_size: usize,
+    // Longest a client may take over a request head once it has started sending
+    // one, so that trickling bytes under the idle timeout cannot hold a worker
+    pub header_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_size: 8 * 1024,
+            max_body_size: 1024 * 1024,
+            max_decompressed_size: 8 * 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// A request read off the wire: the raw head (request line and headers), the
+// body with any transfer coding removed, and trailer fields sent after a chunked body
+#[derive(Debug)]
+pub struct RawRequest {
+    pub head: Vec<u8>,
+    pub body: Vec<u8>,
+    pub trailers: HeaderMap,
+}
+
+// How the end of a request body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+// Errors that can occur while reading a request
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Malformed(httparse::Error),
+    InvalidContentLength,
+    InvalidTransferEncoding,
+    UnsupportedTransferEncoding,
+    InvalidChunk,
+    UnsupportedContentEncoding,
+    InvalidCompressedBody(InflateError),
+    InvalidMethod,
+    InvalidTarget,
+    InvalidHeader,
+    InvalidEncoding,
+    UnexpectedEof,
+    HeadersTooLarge,
+    BodyTooLarge,
+    // The request head took longer than `Limits::header_timeout`
+    HeaderTimeout,
+}
+
+impl ReadError {
+    // Status code to answer with, if the client should get a response at all
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            ReadError::Io(_) | ReadError::UnexpectedEof => None,
+            ReadError::Malformed(httparse::Error::TooManyHeaders) => {
+                Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+            }
+            ReadError::Malformed(_)
+            | ReadError::InvalidContentLength
+            | ReadError::InvalidTransferEncoding
+            | ReadError::InvalidChunk
+            | ReadError::InvalidCompressedBody(InflateError::Corrupt(_))
+            | ReadError::InvalidMethod
+            | ReadError::InvalidTarget
+            | ReadError::InvalidHeader
+            | ReadError::InvalidEncoding => Some(StatusCode::BAD_REQUEST),
+            ReadError::HeadersTooLarge => Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            ReadError::BodyTooLarge | ReadError::InvalidCompressedBody(InflateError::TooLarge) => {
+                Some(StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            ReadError::UnsupportedContentEncoding => Some(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            ReadError::UnsupportedTransferEncoding => Some(StatusCode::NOT_IMPLEMENTED),
+            ReadError::HeaderTimeout => Some(StatusCode::REQUEST_TIMEOUT),
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(err) => write!(f, "I/O error: {}", err),
+            ReadError::Malformed(err) => write!(f, "malformed request: {}", err),
+            ReadError::InvalidContentLength => write!(f, "invalid Content-Length header"),
+            ReadError::InvalidTransferEncoding => write!(f, "invalid Transfer-Encoding header"),
+            ReadError::UnsupportedTransferEncoding => {
+                write!(f, "unsupported transfer coding; only chunked is accepted")
+            }
+            ReadError::InvalidChunk => write!(f, "malformed chunked body"),
+            ReadError::UnsupportedContentEncoding => {
+                write!(
+                    f,
+                    "unsupported Content-Encoding; only gzip and deflate are accepted"
+                )
+            }
+            ReadError::InvalidCompressedBody(err) => write!(f, "invalid compressed body: {}", err),
+            ReadError::InvalidMethod => write!(f, "invalid request method"),
+            ReadError::InvalidTarget => write!(f, "invalid request target"),
+            ReadError::InvalidHeader => write!(f, "invalid header"),
+            ReadError::InvalidEncoding => write!(f, "malformed percent-encoding in request target"),
+            ReadError::UnexpectedEof => write!(f, "connection closed mid-request"),
+            ReadError::HeadersTooLarge => write!(f, "request headers too large"),
+            ReadError::BodyTooLarge => write!(f, "request body too large"),
+            ReadError::HeaderTimeout => write!(f, "request head not received in time"),
+        }
+    }
+}
+
+impl Error for ReadError {}
+
+impl From<io::Error> for ReadError {
+    fn from(err: io::Error) -> Self {
+        ReadError::Io(err)
+    }
+}
+
+// Buffered reader that pulls complete requests off a stream
+pub struct RequestReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+    limits: Limits,
+}
+
+impl<R: Read> RequestReader<R> {
+    pub fn new(inner: R, limits: Limits) -> Self {
+        RequestReader {
+            inner,
+            buffer: Vec::new(),
+            limits,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    // Read the next request, returning None if the stream closed before any bytes arrived
+    pub fn read_request(&mut self) -> Result<Option<RawRequest>, ReadError> {
+        let head_len = match self.read_head()? {
+            Some(head_len) => head_len,
+            None => return Ok(None),
+        };
+
+        let framing = parse_framing(&self.buffer[..head_len])?;
+        let head: Vec<u8> = self.buffer.drain(..head_len).collect();
+        let (body, trailers) = match framing {
+            Framing::Length(length) => (self.read_fixed(length)?, HeaderMap::new()),
+            Framing::Chunked => self.read_chunked()?,
+        };
+
+        Ok(Some(RawRequest {
+            head,
+            body,
+            trailers,
+        }))
+    }
+
+    // Read a body of exactly `length` bytes
+    fn read_fixed(&mut self, length: usize) -> Result<Vec<u8>, ReadError> {
+        if length > self.limits.max_body_size {
+            return Err(ReadError::BodyTooLarge);
+        }
+
+        while self.buffer.len() < length {
+            if self.fill_buffer()? == 0 {
+                return Err(ReadError::UnexpectedEof);
+            }
+        }
+
+        Ok(self.buffer.drain(..length).collect())
+    }
+
+    // Decode a chunked body and the trailer section that ends it
+    fn read_chunked(&mut self) -> Result<(Vec<u8>, HeaderMap), ReadError> {
+        let mut body = Vec::new();
+
+        loop {
+            let line_len = self.read_line()?;
+            let size = parse_chunk_size(&self.buffer[..line_len]).ok_or(ReadError::InvalidChunk)?;
+            self.buffer.drain(..line_len + 2);
+            if size == 0 {
+                break;
+            }
+
+            if size > self.limits.max_body_size - body.len() {
+                return Err(ReadError::BodyTooLarge);
+            }
+            while self.buffer.len() < size + 2 {
+                if self.fill_buffer()? == 0 {
+                    return Err(ReadError::UnexpectedEof);
+                }
+            }
+            if &self.buffer[size..size + 2] != b"\r\n" {
+                return Err(ReadError::InvalidChunk);
+            }
+            body.extend(self.buffer.drain(..size));
+            self.buffer.drain(..2);
+        }
+
+        let trailers = self.read_trailers()?;
+        Ok((body, trailers))
+    }
+
+    // Read until the buffer holds a complete chunk-size line and return its length without CRLF
+    fn read_line(&mut self) -> Result<usize, ReadError> {
+        let mut searched = 0;
+        loop {
+            if let Some(index) = self.buffer[searched..]
+                .windows(2)
+                .position(|window| window == b"\r\n")
+            {
+                return Ok(searched + index);
+            }
+            if self.buffer.len() > MAX_CHUNK_LINE {
+                return Err(ReadError::InvalidChunk);
+            }
+            // The CR of a split CRLF may already be buffered
+            searched = self.buffer.len().saturating_sub(1);
+            if self.fill_buffer()? == 0 {
+                return Err(ReadError::UnexpectedEof);
+            }
+        }
+    }
+
+    // Read the trailer fields after the last chunk, bounded like the header section
+    fn read_trailers(&mut self) -> Result<HeaderMap, ReadError> {
+        loop {
+            let mut fields = [httparse::EMPTY_HEADER; MAX_HEADERS];
+            match httparse::parse_headers(&self.buffer, &mut fields) {
+                Ok(httparse::Status::Complete((len, fields))) => {
+                    if len > self.limits.max_header_size {
+                        return Err(ReadError::HeadersTooLarge);
+                    }
+                    let mut trailers = HeaderMap::new();
+                    for field in fields {
+                        let name = HeaderName::from_bytes(field.name.as_bytes())
+                            .map_err(|_| ReadError::InvalidHeader)?;
+                        let value = HeaderValue::from_bytes(field.value)
+                            .map_err(|_| ReadError::InvalidHeader)?;
+                        trailers.append(name, value);
+                    }
+                    self.buffer.drain(..len);
+                    return Ok(trailers);
+                }
+                Ok(httparse::Status::Partial) => {
+                    if self.buffer.len() > self.limits.max_header_size {
+                        return Err(ReadError::HeadersTooLarge);
+                    }
+                }
+                Err(err) => return Err(ReadError::Malformed(err)),
+            }
+
+            if self.fill_buffer()? == 0 {
+                return Err(ReadError::UnexpectedEof);
+            }
+        }
+    }
+
+    // Whether input past the last request is already buffered, as with pipelining
+    pub fn has_buffered_input(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    // Read until the header section is complete and return its length in bytes
+    //
+    // The head must be complete within the header timeout of its first byte
+    // arriving. The deadline is checked between reads, so a read in progress
+    // can still take up to the stream's own read timeout.
+    fn read_head(&mut self) -> Result<Option<usize>, ReadError> {
+        let mut started = None;
+        loop {
+            if !self.buffer.is_empty() {
+                let started = *started.get_or_insert_with(Instant::now);
+                if started.elapsed() > self.limits.header_timeout {
+                    return Err(ReadError::HeaderTimeout);
+                }
+
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut request = httparse::Request::new(&mut headers);
+
+                match request.parse(&self.buffer) {
+                    Ok(httparse::Status::Complete(head_len)) => {
+                        if head_len > self.limits.max_header_size {
+                            return Err(ReadError::HeadersTooLarge);
+                        }
+                        return Ok(Some(head_len));
+                    }
+                    Ok(httparse::Status::Partial) => {
+                        if self.buffer.len() > self.limits.max_header_size {
+                            return Err(ReadError::HeadersTooLarge);
+                        }
+                    }
+                    Err(err) => return Err(ReadError::Malformed(err)),
+                }
+            }
+
+            if self.fill_buffer()? == 0 {
+                return if self.buffer.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(ReadError::UnexpectedEof)
+                };
+            }
+        }
+    }
+
+    // Append the next chunk from the stream to the buffer
+    fn fill_buffer(&mut self) -> Result<usize, ReadError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let read_bytes = loop {
+            match self.inner.read(&mut chunk) {
+                Ok(read_bytes) => break read_bytes,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        };
+        self.buffer.extend_from_slice(&chunk[..read_bytes]);
+        Ok(read_bytes)
+    }
+}
+
+// Work out how the body is delimited from a complete request head
+//
+// Without Transfer-Encoding the Content-Length applies, defaulting to zero.
+fn parse_framing(head: &[u8]) -> Result<Framing, ReadError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(head).map_err(ReadError::Malformed)?;
+
+    let mut content_length = None;
+    let mut codings = Vec::new();
+    for header in request.headers.iter() {
+        if header.name.eq_ignore_ascii_case("Transfer-Encoding") {
+            let value = std::str::from_utf8(header.value)
+                .map_err(|_| ReadError::InvalidTransferEncoding)?;
+            codings.extend(
+                value
+                    .split(',')
+                    .map(|coding| coding.trim().to_ascii_lowercase())
+                    .filter(|coding| !coding.is_empty()),
+            );
+        } else if header.name.eq_ignore_ascii_case("Content-Length") {
+            let value = std::str::from_utf8(header.value)
+                .ok()
+                .and_then(|value| value.trim().parse::<usize>().ok())
+                .ok_or(ReadError::InvalidContentLength)?;
+
+            // Conflicting duplicate lengths are a request smuggling vector
+            if content_length.is_some_and(|existing| existing != value) {
+                return Err(ReadError::InvalidContentLength);
+            }
+            content_length = Some(value);
+        }
+    }
+
+    if codings.is_empty() {
+        return Ok(Framing::Length(content_length.unwrap_or(0)));
+    }
+    // A body must be delimited one way only, and chunked must come last to delimit it
+    if content_length.is_some() || codings.last().map(String::as_str) != Some("chunked") {
+        return Err(ReadError::InvalidTransferEncoding);
+    }
+    if codings.len() > 1 {
+        return Err(ReadError::UnsupportedTransferEncoding);
+    }
+
+    Ok(Framing::Chunked)
+}
+
+// A parsed HTTP request handed to route handlers
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub query_params: QueryParams,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+    pub trailers: HeaderMap,
+    pub params: Params,
+    // Application state registered on the router
+    pub app_state: Arc<AppState>,
+}
+
+impl Request {
+    // Build a request from the head parsed by httparse and the body read after it
+    pub fn from_parsed(parsed: &httparse::Request, body: Vec<u8>) -> Result<Self, ReadError> {
+        let method = parsed
+            .method
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+            .ok_or(ReadError::InvalidMethod)?;
+
+        let target = parsed.path.ok_or(ReadError::InvalidTarget)?;
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+
+        // Reject targets the router and handlers would be unable to decode
+        if path
+            .split('/')
+            .any(|segment| percent_decode(segment, false).is_err())
+        {
+            return Err(ReadError::InvalidEncoding);
+        }
+        let query_params = match &query {
+            Some(query) => QueryParams::parse(query).map_err(|_| ReadError::InvalidEncoding)?,
+            None => QueryParams::default(),
+        };
+
+        let version = match parsed.version {
+            Some(0) => Version::HTTP_10,
+            _ => Version::HTTP_11,
+        };
+
+        let mut headers = HeaderMap::with_capacity(parsed.headers.len());
+        for header in parsed.headers.iter() {
+            let name = HeaderName::from_bytes(header.name.as_bytes())
+                .map_err(|_| ReadError::InvalidHeader)?;
+            let value =
+                HeaderValue::from_bytes(header.value).map_err(|_| ReadError::InvalidHeader)?;
+            headers.append(name, value);
+        }
+
+        Ok(Request {
+            method,
+            path,
+            query,
+            query_params,
+            version,
+            headers,
+            body,
+            trailers: HeaderMap::new(),
+            params: Params::default(),
+            app_state: Arc::default(),
+        })
+    }
+
+    // Build a request as the server would parse it from `method target HTTP/1.1`
+    #[cfg(test)]
+    pub(crate) fn for_test(
+        method: &str,
+        target: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Request {
+        let mut head = format!("{} {} HTTP/1.1\r\n", method, target);
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut parsed_headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut parsed = httparse::Request::new(&mut parsed_headers);
+        parsed.parse(head.as_bytes()).unwrap();
+        Request::from_parsed(&parsed, body.to_vec()).unwrap()
+    }
+
+    // Get the first value of a header as a string, if present and valid UTF-8
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    // Get the first value of a query string parameter
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_params.get(name)
+    }
+
+    // Get a parameter captured from the matched route pattern
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    // Get a value of application state registered with `Router::state`
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.app_state.get()
+    }
+
+    // Whether the client wants the connection kept open after this request
+    //
+    // HTTP/1.1 connections persist unless the client sends `Connection: close`;
+    // HTTP/1.0 connections close unless it sends `Connection: keep-alive`.
+    pub fn wants_keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.headers
+                .get_all(header::CONNECTION)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+
+        if self.version == Version::HTTP_10 {
+            has_token("keep-alive")
+        } else {
+            !has_token("close")
+        }
+    }
+
+    // Undo the body's Content-Encoding so handlers see the original bytes
+    //
+    // Codings are removed in the reverse of the order they were applied, and
+    // the headers are updated to describe the decoded body.
+    pub fn decode_body(&mut self, max_size: usize) -> Result<(), ReadError> {
+        let codings: Vec<String> = self
+            .headers
+            .get_all(header::CONTENT_ENCODING)
+            .iter()
+            .map(|value| {
+                value
+                    .to_str()
+                    .map_err(|_| ReadError::UnsupportedContentEncoding)
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .map(|coding| coding.trim().to_ascii_lowercase())
+            .filter(|coding| !coding.is_empty() && coding != "identity")
+            .collect();
+        if codings.is_empty() {
+            return Ok(());
+        }
+
+        for coding in codings.iter().rev() {
+            let format = match coding.as_str() {
+                "gzip" | "x-gzip" => Format::Gzip,
+                "deflate" => Format::Zlib,
+                _ => return Err(ReadError::UnsupportedContentEncoding),
+            };
+            self.body = inflate::decompress(&self.body, format, max_size)
+                .map_err(ReadError::InvalidCompressedBody)?;
+        }
+
+        self.headers.remove(header::CONTENT_ENCODING);
+        self.headers
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+        Ok(())
+    }
+
+    // Decode a urlencoded or multipart/form-data body with the default limits
+    //
+    // Files may be as large as the server's body size limit, when that is
+    // registered as application state.
+    pub fn form(&self) -> Result<Form, FormError> {
+        let mut options = FormOptions::default();
+        if let Some(limits) = self.state::<Limits>() {
+            options.max_file_size = limits.max_body_size as u64;
+        }
+        self.form_with(&options)
+    }
+
+    pub fn form_with(&self, options: &FormOptions) -> Result<Form, FormError> {
+        form::parse(self, options)
+    }
+
+    // Deserialize a JSON body, which must be declared with a JSON Content-Type
+    //
+    // Errors carry the status to answer with; `JsonError::to_response` turns
+    // them into a problem details response.
+    pub fn json<T: FromJson>(&self) -> Result<T, JsonError> {
+        if !self.header("content-type").is_some_and(json::is_json_type) {
+            return Err(JsonError::UnsupportedMediaType);
+        }
+        T::from_json(&json::parse_bytes(&self.body)?)
+    }
+
+    // Get the body as text, replacing invalid UTF-8 sequences
+    pub fn body_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out input a few bytes per read, so delimiters arrive split
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    fn chunked(body: &str) -> Vec<u8> {
+        format!(
+            "POST /upload HTTP/1.1\r\nHost: x\r\nTransfer-Encoding: chunked\r\n\r\n{}",
+            body
+        )
+        .into_bytes()
+    }
+
+    fn read(input: &[u8], limits: Limits) -> Result<Option<RawRequest>, ReadError> {
+        RequestReader::new(Trickle(input), limits).read_request()
+    }
+
+    #[test]
+    fn decodes_chunked_bodies_with_trailers() {
+        let input =
+            chunked("5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nChecksum: abc\r\nX-A: 1\r\n\r\n");
+        let request = read(&input, Limits::default()).unwrap().unwrap();
+        assert_eq!(request.body, b"hello world");
+        assert_eq!(request.trailers["checksum"], "abc");
+        assert_eq!(request.trailers["x-a"], "1");
+    }
+
+    #[test]
+    fn keeps_pipelined_requests_apart() {
+        let mut input = chunked("3\r\nabc\r\n0\r\n\r\n");
+        input.extend_from_slice(b"GET /next HTTP/1.1\r\nHost: x\r\n\r\n");
+        let mut reader = RequestReader::new(Trickle(&input), Limits::default());
+        assert_eq!(reader.read_request().unwrap().unwrap().body, b"abc");
+        let next = reader.read_request().unwrap().unwrap();
+        assert!(next.head.starts_with(b"GET /next"));
+        assert!(reader.read_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_chunks() {
+        for body in [
+            "zz\r\nhello\r\n0\r\n\r\n",
+            "\r\n",
+            "5\r\nhelloXX0\r\n\r\n",
+            "-5\r\nhello\r\n0\r\n\r\n",
+            "ffffffffffffffffffff\r\n",
+        ] {
+            assert!(
+                matches!(
+                    read(&chunked(body), Limits::default()),
+                    Err(ReadError::InvalidChunk)
+                ),
+                "{:?}",
+                body
+            );
+        }
+
+        let long_extension = format!("1;{}\r\n", "x".repeat(MAX_CHUNK_LINE + 10));
+        assert!(matches!(
+            read(&chunked(&long_extension), Limits::default()),
+            Err(ReadError::InvalidChunk)
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_trailers() {
+        assert!(matches!(
+            read(&chunked("0\r\nno colon here\r\n\r\n"), Limits::default()),
+            Err(ReadError::Malformed(_))
+        ));
+
+        let limits = Limits {
+            max_header_size: 128,
+            ..Limits::default()
+        };
+        let trailer = format!("0\r\nX-Big: {}\r\n\r\n", "v".repeat(200));
+        assert!(matches!(
+            read(&chunked(&trailer), limits),
+            Err(ReadError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_chunked_bodies() {
+        for body in [
+            "5\r\nhel",
+            "5\r\nhello\r\n",
+            "5\r\nhello\r\n0\r\nX-A: 1\r\n",
+        ] {
+            assert!(
+                matches!(
+                    read(&chunked(body), Limits::default()),
+                    Err(ReadError::UnexpectedEof)
+                ),
+                "{:?}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn limits_the_decoded_body_size() {
+        let limits = Limits {
+            max_body_size: 8,
+            ..Limits::default()
+        };
+        assert!(read(&chunked("4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n"), limits).is_ok());
+        assert!(matches!(
+            read(&chunked("4\r\nabcd\r\n5\r\nefghi\r\n0\r\n\r\n"), limits),
+            Err(ReadError::BodyTooLarge)
+        ));
+    }
+
+    #[test]
+    fn times_out_slow_request_heads() {
+        // Sends one byte per read, pausing before each
+        struct Drip<'a>(&'a [u8]);
+
+        impl Read for Drip<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                std::thread::sleep(Duration::from_millis(5));
+                let len = buf.len().min(self.0.len()).min(1);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        let limits = Limits {
+            header_timeout: Duration::from_millis(50),
+            ..Limits::default()
+        };
+        let head = b"GET /a-long-enough-path HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(matches!(
+            RequestReader::new(Drip(head), limits).read_request(),
+            Err(ReadError::HeaderTimeout)
+        ));
+        assert!(RequestReader::new(&head[..], limits)
+            .read_request()
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn rejects_ambiguous_framing() {
+        let both = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(matches!(
+            read(both, Limits::default()),
+            Err(ReadError::InvalidTransferEncoding)
+        ));
+        let conflicting = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd";
+        assert!(matches!(
+            read(conflicting, Limits::default()),
+            Err(ReadError::InvalidContentLength)
+        ));
+        let gzip_last = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n";
+        assert!(matches!(
+            read(gzip_last, Limits::default()),
+            Err(ReadError::InvalidTransferEncoding)
+        ));
+    }
+}
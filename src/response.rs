@@ -0,0 +1,401 @@
+use crate::chunked::ChunkedWriter;
+use crate::json::ToJson;
+use http::header::{self, HeaderMap, HeaderValue, IntoHeaderName};
+use http::StatusCode;
+use log::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+
+// Body of a response: nothing, an in-memory buffer, a file, or a stream
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // `length` bytes of a file from its current position; seekable, so ranges can be served
+    File {
+        file: File,
+        length: u64,
+    },
+    Reader {
+        reader: Box<dyn Read + Send>,
+        length: u64,
+    },
+    // A stream whose length is unknown until it ends, sent with chunked encoding
+    Stream(Box<dyn Read + Send>),
+    // Pieces of output produced one at a time, each sent as soon as it is yielded;
+    // an error aborts the response mid-body
+    Chunks(Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>),
+}
+
+impl Body {
+    // Wrap a stream that will yield exactly `length` bytes
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, length: u64) -> Self {
+        Body::Reader {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    // Serve the rest of a file from its current position
+    pub fn from_file(mut file: File) -> io::Result<Self> {
+        let length = file.metadata()?.len();
+        let position = file.stream_position()?;
+        Ok(Body::File {
+            file,
+            length: length.saturating_sub(position),
+        })
+    }
+
+    // Wrap a stream of unknown length; it is sent as it is read
+    pub fn from_stream<R: Read + Send + 'static>(reader: R) -> Self {
+        Body::Stream(Box::new(reader))
+    }
+
+    // Send each item of an iterator as it is produced, e.g. rows of a large report
+    pub fn from_chunks<I, T>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        I::IntoIter: Send + 'static,
+        T: Into<Vec<u8>>,
+    {
+        Body::Chunks(Box::new(chunks.into_iter().map(|chunk| Ok(chunk.into()))))
+    }
+
+    // Length in bytes, or None for a stream that has not ended yet
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::File { length, .. } | Body::Reader { length, .. } => Some(*length),
+            Body::Stream(_) | Body::Chunks(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Body::Empty"),
+            Body::Bytes(bytes) => write!(f, "Body::Bytes({} bytes)", bytes.len()),
+            Body::File { length, .. } => write!(f, "Body::File({} bytes)", length),
+            Body::Reader { length, .. } => write!(f, "Body::Reader({} bytes)", length),
+            Body::Stream(_) => write!(f, "Body::Stream"),
+            Body::Chunks(_) => write!(f, "Body::Chunks"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+// An HTTP response returned by route handlers
+#[derive(Debug)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Self {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            body: Body::Empty,
+        }
+    }
+
+    // HTML response with the given status
+    pub fn html(status: StatusCode, body: impl Into<String>) -> Self {
+        Response::new(status)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(body.into())
+    }
+
+    // Plain text response with the given status
+    pub fn text(status: StatusCode, body: impl Into<String>) -> Self {
+        Response::new(status)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body.into())
+    }
+
+    // JSON response with an already serialized body
+    pub fn json(status: StatusCode, body: impl Into<String>) -> Self {
+        Response::new(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.into())
+    }
+
+    // JSON response serialized from a value
+    pub fn json_value<T: ToJson + ?Sized>(status: StatusCode, value: &T) -> Self {
+        Response::json(status, value.to_json().to_string())
+    }
+
+    // Temporary redirect to another location
+    pub fn redirect(location: &str) -> Self {
+        Response::new(StatusCode::FOUND).header(header::LOCATION, location)
+    }
+
+    // Set the status code
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    // Set a header, replacing any existing values; invalid values are logged and skipped
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: IntoHeaderName,
+        V: TryInto<HeaderValue>,
+        V::Error: fmt::Display,
+    {
+        match value.try_into() {
+            Ok(value) => {
+                self.headers.insert(name, value);
+            }
+            Err(err) => error!("Invalid header value: {}", err),
+        }
+        self
+    }
+
+    // Set the body
+    pub fn body(mut self, body: impl Into<Body>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // Serialize the status line, headers and body to a stream
+    pub fn write_to<W: Write>(self, stream: &mut W) -> io::Result<()> {
+        self.write(stream, true, true)
+    }
+
+    // Serialize only the status line and headers, as for a HEAD request
+    //
+    // Content-Length still reflects the body that a GET would have returned.
+    pub fn write_head_to<W: Write>(self, stream: &mut W) -> io::Result<()> {
+        self.write(stream, false, true)
+    }
+
+    // Serialize for an HTTP/1.0 client, which cannot decode chunked bodies
+    //
+    // Streams are sent as-is and delimited by closing the connection, so the
+    // caller must close it afterwards when `body.len()` is None.
+    pub fn write_unchunked_to<W: Write>(self, stream: &mut W, send_body: bool) -> io::Result<()> {
+        self.write(stream, send_body, false)
+    }
+
+    fn write<W: Write>(self, stream: &mut W, send_body: bool, chunked: bool) -> io::Result<()> {
+        let Response {
+            status,
+            headers,
+            body,
+        } = self;
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            status.as_str(),
+            status.canonical_reason().unwrap_or("Unknown")
+        )
+        .into_bytes();
+
+        for (name, value) in headers.iter() {
+            // Framing is derived from the body, never taken from the handler
+            if name == header::CONTENT_LENGTH || name == header::TRANSFER_ENCODING {
+                continue;
+            }
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        // Responses that can never carry a body must not advertise a length either
+        let bodiless = status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED;
+        if !bodiless {
+            match body.len() {
+                Some(length) => {
+                    head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes())
+                }
+                None if chunked => head.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+                None => {}
+            }
+        }
+        head.extend_from_slice(b"\r\n");
+
+        stream.write_all(&head)?;
+
+        if !send_body || bodiless {
+            return stream.flush();
+        }
+
+        match body {
+            Body::Empty => {}
+            Body::Bytes(bytes) => stream.write_all(&bytes)?,
+            Body::File { file, length } => copy_exact(file, length, stream)?,
+            Body::Reader { reader, length } => copy_exact(reader, length, stream)?,
+            Body::Stream(mut reader) if chunked => {
+                let mut writer = ChunkedWriter::new(&mut *stream);
+                io::copy(&mut reader, &mut writer)?;
+                writer.finish(&HeaderMap::new())?;
+            }
+            Body::Stream(mut reader) => {
+                io::copy(&mut reader, stream)?;
+            }
+            Body::Chunks(chunks) if chunked => {
+                let mut writer = ChunkedWriter::new(&mut *stream);
+                for chunk in chunks {
+                    writer.write_all(&chunk?)?;
+                    writer.flush()?;
+                }
+                writer.finish(&HeaderMap::new())?;
+            }
+            Body::Chunks(chunks) => {
+                for chunk in chunks {
+                    stream.write_all(&chunk?)?;
+                    stream.flush()?;
+                }
+            }
+        }
+
+        stream.flush()
+    }
+}
+
+// Copy exactly `length` bytes, failing if the source runs out early
+fn copy_exact<R: Read, W: Write>(reader: R, length: u64, stream: &mut W) -> io::Result<()> {
+    let copied = io::copy(&mut reader.take(length), stream)?;
+    if copied != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "response body shorter than its declared length",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(response: Response) -> String {
+        let mut output = Vec::new();
+        response.write_to(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn writes_status_line_and_headers() {
+        let response = Response::text(StatusCode::NOT_FOUND, "missing");
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 404 Not Found\r\n\
+             content-type: text/plain; charset=utf-8\r\n\
+             Content-Length: 7\r\n\r\nmissing"
+        );
+
+        let response = Response::new(StatusCode::from_u16(599).unwrap());
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 599 Unknown\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn replaces_framing_headers_set_by_handlers() {
+        let response = Response::new(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, "100")
+            .header(header::TRANSFER_ENCODING, "gzip")
+            .body("abc");
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc"
+        );
+    }
+
+    #[test]
+    fn omits_length_and_body_for_bodiless_statuses() {
+        for status in [StatusCode::NO_CONTENT, StatusCode::NOT_MODIFIED] {
+            let response = Response::new(status).body("ignored");
+            assert_eq!(
+                written(response),
+                format!(
+                    "HTTP/1.1 {} {}\r\n\r\n",
+                    status.as_str(),
+                    status.canonical_reason().unwrap()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn sends_only_the_head_for_head_requests() {
+        let mut output = Vec::new();
+        Response::new(StatusCode::OK)
+            .body("hello")
+            .write_head_to(&mut output)
+            .unwrap();
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+    }
+
+    #[test]
+    fn chunks_streams_of_unknown_length() {
+        let response = Response::new(StatusCode::OK).body(Body::from_stream(&b"hello"[..]));
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"
+        );
+
+        let response = Response::new(StatusCode::OK).body(Body::from_chunks(["ab", "cde"]));
+        assert_eq!(
+            written(response),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+             2\r\nab\r\n3\r\ncde\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn writes_streams_unchunked_for_http_1_0() {
+        let mut output = Vec::new();
+        Response::new(StatusCode::OK)
+            .body(Body::from_chunks(["ab", "cde"]))
+            .write_unchunked_to(&mut output, true)
+            .unwrap();
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\n\r\nabcde");
+
+        let mut output = Vec::new();
+        Response::new(StatusCode::OK)
+            .body(Body::from_stream(&b"hello"[..]))
+            .write_unchunked_to(&mut output, false)
+            .unwrap();
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\n\r\n");
+    }
+
+    #[test]
+    fn fails_when_a_sized_body_runs_short() {
+        let response = Response::new(StatusCode::OK).body(Body::from_reader(&b"abc"[..], 5));
+        let mut output = Vec::new();
+        let err = response.write_to(&mut output).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(output, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabc");
+    }
+}
@@ -0,0 +1,815 @@
+use crate::compression::Compression;
+use crate::conditional::CachePolicy;
+use crate::middleware::Middleware;
+use crate::request::Request;
+use crate::response::Response;
+use crate::state::AppState;
+use crate::url::percent_decode;
+use http::Method;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Something that answers requests routed to it
+//
+// Implemented for plain functions and for closures, which can capture
+// configuration or shared resources. Application-wide values are better kept
+// in the router's state and read through `Request::state`.
+pub trait Handler: Send + Sync {
+    fn call(&self, request: &Request) -> Response;
+
+    // Name shown in the route table
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn call(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+// Parameters captured from named (`:id`) and catch-all (`*rest`) segments
+#[derive(Debug, Clone, Default)]
+pub struct Params {
+    entries: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+// Outcome of looking up a request in the router
+pub enum RouteMatch {
+    Found {
+        handler: Arc<dyn Handler>,
+        params: Params,
+        cache_policy: Option<CachePolicy>,
+        compression: Option<Compression>,
+        // Middleware scoped to prefixes of the route and to the route itself, outermost first
+        middleware: Vec<Arc<dyn Middleware>>,
+    },
+    // The path exists but has no handler for the method; carries the methods it
+    // does accept and the middleware of the route, which still wraps the reply
+    MethodNotAllowed {
+        allowed: Vec<Method>,
+        middleware: Vec<Arc<dyn Middleware>>,
+    },
+    // OPTIONS request without an explicit handler; carries the methods the
+    // target accepts and the middleware of the route, so it sees preflights
+    Options {
+        allowed: Vec<Method>,
+        middleware: Vec<Arc<dyn Middleware>>,
+    },
+    NotFound,
+}
+
+// A node in the routing trie, one per path segment
+//
+// Lookup precedence at every level is: literal segment, then named parameter,
+// then catch-all, backtracking when a more specific branch fails to match.
+#[derive(Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    param: Option<(String, Box<Node>)>,
+    catch_all: Option<(String, Endpoint)>,
+    endpoint: Endpoint,
+    // Runs for every route at or below this node
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+// Handlers and settings for one route pattern
+#[derive(Default)]
+struct Endpoint {
+    handlers: HashMap<Method, Arc<dyn Handler>>,
+    cache_policy: Option<CachePolicy>,
+    compression: Option<Compression>,
+    // Runs for this route only, inside the middleware of its prefixes; set by mounting
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl Endpoint {
+    // Endpoints only match once a handler is registered, not just a policy
+    fn is_routable(&self) -> bool {
+        !self.handlers.is_empty()
+    }
+
+    // Take over the handlers and settings of another endpoint for the same pattern
+    fn merge(&mut self, other: Endpoint, pattern: &str) {
+        for (method, handler) in other.handlers {
+            let duplicate = self.handlers.insert(method.clone(), handler).is_some();
+            assert!(!duplicate, "duplicate route {} {}", method, pattern);
+        }
+        if other.cache_policy.is_some() {
+            self.cache_policy = other.cache_policy;
+        }
+        if other.compression.is_some() {
+            self.compression = other.compression;
+        }
+        self.middleware.extend(other.middleware);
+    }
+}
+
+impl Node {
+    // Find the endpoint for a path, collecting captured parameters and the
+    // middleware of every node on the way down
+    fn lookup<'a>(
+        &'a self,
+        segments: &[&str],
+        params: &mut Vec<(String, String)>,
+        middleware: &mut Vec<Arc<dyn Middleware>>,
+    ) -> Option<&'a Endpoint> {
+        let endpoint = self.lookup_below(segments, params, middleware)?;
+        middleware.splice(0..0, self.middleware.iter().cloned());
+        Some(endpoint)
+    }
+
+    // Move the middleware of this node and every node below it onto the
+    // endpoints it covers, behind `outer`, so that grafting the trie elsewhere
+    // cannot spread it to routes that share a prefix
+    fn push_down_middleware(&mut self, outer: &[Arc<dyn Middleware>]) {
+        let mut chain = outer.to_vec();
+        chain.append(&mut self.middleware);
+
+        self.endpoint.middleware.splice(0..0, chain.iter().cloned());
+        if let Some((_, endpoint)) = &mut self.catch_all {
+            endpoint.middleware.splice(0..0, chain.iter().cloned());
+        }
+        if let Some((_, child)) = &mut self.param {
+            child.push_down_middleware(&chain);
+        }
+        for child in self.literals.values_mut() {
+            child.push_down_middleware(&chain);
+        }
+    }
+
+    fn lookup_below<'a>(
+        &'a self,
+        segments: &[&str],
+        params: &mut Vec<(String, String)>,
+        middleware: &mut Vec<Arc<dyn Middleware>>,
+    ) -> Option<&'a Endpoint> {
+        let (segment, rest) = match segments.split_first() {
+            Some(split) => split,
+            None => return Some(&self.endpoint).filter(|endpoint| endpoint.is_routable()),
+        };
+
+        if let Some(child) = self.literals.get(*segment) {
+            if let Some(endpoint) = child.lookup(rest, params, middleware) {
+                return Some(endpoint);
+            }
+        }
+
+        if let Some((name, child)) = &self.param {
+            if !segment.is_empty() {
+                params.push((name.clone(), segment.to_string()));
+                if let Some(endpoint) = child.lookup(rest, params, middleware) {
+                    return Some(endpoint);
+                }
+                params.pop();
+            }
+        }
+
+        if let Some((name, endpoint)) = &self.catch_all {
+            if endpoint.is_routable() {
+                params.push((name.clone(), segments.join("/")));
+                return Some(endpoint);
+            }
+        }
+
+        None
+    }
+
+    // Gather every method registered at or below this node
+    fn collect_methods(&self, methods: &mut HashMap<Method, Arc<dyn Handler>>) {
+        methods.extend(
+            self.endpoint
+                .handlers
+                .iter()
+                .map(|(method, handler)| (method.clone(), Arc::clone(handler))),
+        );
+        if let Some((_, endpoint)) = &self.catch_all {
+            methods.extend(
+                endpoint
+                    .handlers
+                    .iter()
+                    .map(|(method, handler)| (method.clone(), Arc::clone(handler))),
+            );
+        }
+        if let Some((_, child)) = &self.param {
+            child.collect_methods(methods);
+        }
+        for child in self.literals.values() {
+            child.collect_methods(methods);
+        }
+    }
+
+    // Graft another trie onto this node, as when mounting a router at `pattern`
+    fn merge(&mut self, other: Node, pattern: &str) {
+        self.endpoint.merge(other.endpoint, pattern);
+
+        for (segment, child) in other.literals {
+            let pattern = format!("{}/{}", pattern, segment);
+            self.literals
+                .entry(segment)
+                .or_default()
+                .merge(child, &pattern);
+        }
+
+        if let Some((name, child)) = other.param {
+            let pattern = format!("{}/:{}", pattern, name);
+            let (existing, node) = self
+                .param
+                .get_or_insert_with(|| (name.clone(), Box::default()));
+            assert!(
+                *existing == name,
+                "conflicting parameter names in route {}",
+                pattern
+            );
+            node.merge(*child, &pattern);
+        }
+
+        if let Some((name, endpoint)) = other.catch_all {
+            let pattern = format!("{}/*{}", pattern, name);
+            let (existing, merged) = self
+                .catch_all
+                .get_or_insert_with(|| (name.clone(), Endpoint::default()));
+            assert!(
+                *existing == name,
+                "conflicting catch-all names in route {}",
+                pattern
+            );
+            merged.merge(endpoint, &pattern);
+        }
+    }
+
+    // List the routes at or below this node, whose pattern so far is `pattern`
+    fn collect_routes(
+        &self,
+        pattern: &str,
+        middleware: &[&'static str],
+        routes: &mut Vec<RouteInfo>,
+    ) {
+        let mut middleware = middleware.to_vec();
+        middleware.extend(self.middleware.iter().map(|middleware| middleware.name()));
+
+        let path = if pattern.is_empty() { "/" } else { pattern };
+        self.endpoint.collect_routes(path, &middleware, routes);
+        if let Some((name, endpoint)) = &self.catch_all {
+            endpoint.collect_routes(&format!("{}/*{}", pattern, name), &middleware, routes);
+        }
+        if let Some((name, child)) = &self.param {
+            child.collect_routes(&format!("{}/:{}", pattern, name), &middleware, routes);
+        }
+        for (segment, child) in &self.literals {
+            child.collect_routes(&format!("{}/{}", pattern, segment), &middleware, routes);
+        }
+    }
+}
+
+impl Endpoint {
+    fn collect_routes(
+        &self,
+        pattern: &str,
+        middleware: &[&'static str],
+        routes: &mut Vec<RouteInfo>,
+    ) {
+        let mut middleware = middleware.to_vec();
+        middleware.extend(self.middleware.iter().map(|middleware| middleware.name()));
+        routes.extend(self.handlers.iter().map(|(method, handler)| RouteInfo {
+            method: method.clone(),
+            pattern: pattern.to_string(),
+            handler: handler.name(),
+            middleware: middleware.clone(),
+        }));
+    }
+}
+
+// One row of the route table: a method and pattern with what serves it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteInfo {
+    pub method: Method,
+    pub pattern: String,
+    pub handler: &'static str,
+    // Middleware the request passes through, outermost first
+    pub middleware: Vec<&'static str>,
+}
+
+impl fmt::Display for RouteInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<7} {} -> {}",
+            self.method.as_str(),
+            self.pattern,
+            self.handler
+        )?;
+        if !self.middleware.is_empty() {
+            write!(f, " via {}", self.middleware.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Trie-based router supporting literal, `:name` and `*name` segments
+#[derive(Default)]
+pub struct Router {
+    root: Node,
+    // Runs for every request, routed or not, outermost first
+    middleware: Vec<Arc<dyn Middleware>>,
+    state: Arc<AppState>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    // Register a handler for a method and path pattern such as `/users/:id` or `/static/*path`
+    //
+    // Panics on patterns that conflict with an existing route, since that is a
+    // programming error that should surface at startup.
+    pub fn route(&mut self, method: Method, pattern: &str, handler: impl Handler + 'static) {
+        let handlers = &mut self.endpoint_mut(pattern).handlers;
+        let duplicate = handlers.insert(method.clone(), Arc::new(handler)).is_some();
+        assert!(!duplicate, "duplicate route {} {}", method, pattern);
+    }
+
+    // Set the Cache-Control policy for every method of a route pattern
+    pub fn cache_policy(&mut self, pattern: &str, policy: CachePolicy) {
+        self.endpoint_mut(pattern).cache_policy = Some(policy);
+    }
+
+    // Override the server-wide response compression settings for a route pattern
+    pub fn compression(&mut self, pattern: &str, compression: Compression) {
+        self.endpoint_mut(pattern).compression = Some(compression);
+    }
+
+    // Add middleware that runs around every request, including ones no route matches
+    //
+    // Middleware runs in the order it is added, the first being outermost.
+    pub fn middleware(&mut self, middleware: impl Middleware + 'static) {
+        self.middleware.push(Arc::new(middleware));
+    }
+
+    // Add middleware that runs around the handlers of every route under a path prefix
+    //
+    // It runs inside the global middleware and inside middleware of shorter prefixes.
+    pub fn prefix_middleware(&mut self, prefix: &str, middleware: impl Middleware + 'static) {
+        self.prefix_node_mut(prefix)
+            .middleware
+            .push(Arc::new(middleware));
+    }
+
+    // Mount another router's routes under a path prefix such as `/api/v1`
+    //
+    // Each of its patterns is registered as the prefix followed by the pattern,
+    // so `/users/:id` mounted at `/api/v1` answers `/api/v1/users/:id`. Its global
+    // and prefix middleware is attached to each of its routes, so it never runs
+    // for other routes under the same prefix. Its state joins this router's,
+    // which keeps its own value for types both hold.
+    // Panics if a mounted route conflicts with an existing one.
+    pub fn mount(&mut self, prefix: &str, router: Router) {
+        let Router {
+            mut root,
+            middleware,
+            state,
+        } = router;
+        root.push_down_middleware(&middleware);
+        let pattern = prefix.trim_end_matches('/').to_string();
+        self.prefix_node_mut(prefix).merge(root, &pattern);
+        Arc::make_mut(&mut self.state).extend_missing(&state);
+    }
+
+    // Build a group of routes sharing a path prefix and mount it
+    //
+    // Middleware added to the group with `middleware` only runs for its routes.
+    pub fn group(&mut self, prefix: &str, build: impl FnOnce(&mut Router)) {
+        let mut group = Router::new();
+        build(&mut group);
+        self.mount(prefix, group);
+    }
+
+    // Every registered route with its handler and middleware, sorted by pattern
+    //
+    // Meant for debugging, to see what mounted routers add up to.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let global: Vec<&'static str> = self
+            .middleware
+            .iter()
+            .map(|middleware| middleware.name())
+            .collect();
+        let mut routes = Vec::new();
+        self.root.collect_routes("", &global, &mut routes);
+        routes.sort_by(|a, b| {
+            (a.pattern.as_str(), a.method.as_str()).cmp(&(b.pattern.as_str(), b.method.as_str()))
+        });
+        routes
+    }
+
+    // Make a value available to every handler and middleware through `Request::state`
+    //
+    // Replaces an earlier value of the same type.
+    pub fn state<T: Send + Sync + 'static>(&mut self, value: T) {
+        Arc::make_mut(&mut self.state).insert(value);
+    }
+
+    // The state handed to requests, shared rather than copied per request
+    pub fn app_state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    // Middleware added with `middleware`, outermost first
+    pub fn global_middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    // Find or create the endpoint for a pattern
+    fn endpoint_mut(&mut self, pattern: &str) -> &mut Endpoint {
+        let segments: Vec<&str> = split_path(pattern).collect();
+        let (last, parents) = match segments.split_last() {
+            Some(split) => split,
+            None => return &mut self.root.endpoint,
+        };
+
+        if let Some(name) = last.strip_prefix('*') {
+            let node = Router::node_mut(&mut self.root, parents, pattern);
+            let (existing, endpoint) = node
+                .catch_all
+                .get_or_insert_with(|| (name.to_string(), Endpoint::default()));
+            assert!(
+                existing == name,
+                "conflicting catch-all names in route {}",
+                pattern
+            );
+            return endpoint;
+        }
+
+        &mut Router::node_mut(&mut self.root, &segments, pattern).endpoint
+    }
+
+    // Find or create the node that routes below a prefix hang off
+    fn prefix_node_mut(&mut self, prefix: &str) -> &mut Node {
+        let prefix = prefix.trim_end_matches('/');
+        if prefix.is_empty() {
+            return &mut self.root;
+        }
+        let segments: Vec<&str> = split_path(prefix).collect();
+        assert!(
+            !segments.iter().any(|segment| segment.starts_with('*')),
+            "prefix {} cannot contain a catch-all segment",
+            prefix
+        );
+        Router::node_mut(&mut self.root, &segments, prefix)
+    }
+
+    // Find or create the node for a sequence of literal and parameter segments
+    fn node_mut<'a>(mut node: &'a mut Node, segments: &[&str], pattern: &str) -> &'a mut Node {
+        for segment in segments {
+            assert!(
+                !segment.starts_with('*'),
+                "catch-all segment must be last in route {}",
+                pattern
+            );
+            node = if let Some(name) = segment.strip_prefix(':') {
+                let (existing, child) = node
+                    .param
+                    .get_or_insert_with(|| (name.to_string(), Box::default()));
+                assert!(
+                    existing == name,
+                    "conflicting parameter names in route {}",
+                    pattern
+                );
+                child
+            } else {
+                node.literals.entry(segment.to_string()).or_default()
+            };
+        }
+
+        node
+    }
+
+    pub fn get(&mut self, pattern: &str, handler: impl Handler + 'static) {
+        self.route(Method::GET, pattern, handler);
+    }
+
+    pub fn post(&mut self, pattern: &str, handler: impl Handler + 'static) {
+        self.route(Method::POST, pattern, handler);
+    }
+
+    pub fn put(&mut self, pattern: &str, handler: impl Handler + 'static) {
+        self.route(Method::PUT, pattern, handler);
+    }
+
+    pub fn delete(&mut self, pattern: &str, handler: impl Handler + 'static) {
+        self.route(Method::DELETE, pattern, handler);
+    }
+
+    // Find the handler for a request path and method along with any captured parameters
+    //
+    // Segments are percent-decoded after splitting, so an encoded `%2F` never acts as a separator.
+    // HEAD falls back to the GET handler and OPTIONS is answered automatically unless either
+    // has been registered explicitly.
+    pub fn find(&self, method: &Method, path: &str) -> RouteMatch {
+        if path == "*" {
+            return if *method == Method::OPTIONS {
+                RouteMatch::Options {
+                    allowed: self.all_methods(),
+                    middleware: Vec::new(),
+                }
+            } else {
+                RouteMatch::NotFound
+            };
+        }
+
+        let segments = match decode_segments(path) {
+            Some(segments) => segments,
+            None => return RouteMatch::NotFound,
+        };
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let mut entries = Vec::new();
+        let mut middleware = Vec::new();
+
+        let endpoint = match self.root.lookup(&segments, &mut entries, &mut middleware) {
+            Some(endpoint) => endpoint,
+            None => return RouteMatch::NotFound,
+        };
+        let handlers = &endpoint.handlers;
+
+        middleware.extend(endpoint.middleware.iter().cloned());
+
+        let handler = handlers.get(method).or_else(|| match *method {
+            Method::HEAD => handlers.get(&Method::GET),
+            _ => None,
+        });
+
+        match handler {
+            Some(handler) => RouteMatch::Found {
+                handler: Arc::clone(handler),
+                params: Params { entries },
+                cache_policy: endpoint.cache_policy.clone(),
+                compression: endpoint.compression,
+                middleware,
+            },
+            None if *method == Method::OPTIONS => RouteMatch::Options {
+                allowed: allowed_methods(handlers),
+                middleware,
+            },
+            None => RouteMatch::MethodNotAllowed {
+                allowed: allowed_methods(handlers),
+                middleware,
+            },
+        }
+    }
+
+    // Methods accepted by the route matching a path, empty if no route matches
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let segments = match decode_segments(path) {
+            Some(segments) => segments,
+            None => return Vec::new(),
+        };
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+        self.root
+            .lookup(&segments, &mut Vec::new(), &mut Vec::new())
+            .map(|endpoint| allowed_methods(&endpoint.handlers))
+            .unwrap_or_default()
+    }
+
+    // Methods accepted by any route, used to answer `OPTIONS *`
+    pub fn all_methods(&self) -> Vec<Method> {
+        let mut handlers = HashMap::new();
+        self.root.collect_methods(&mut handlers);
+        allowed_methods(&handlers)
+    }
+}
+
+// Split a request path into percent-decoded segments
+fn decode_segments(path: &str) -> Option<Vec<String>> {
+    split_path(path)
+        .map(|segment| percent_decode(segment, false))
+        .collect::<Result<Vec<String>, _>>()
+        .ok()
+}
+
+// Registered methods plus the implied HEAD and OPTIONS, in a stable order for `Allow` headers
+fn allowed_methods<H>(handlers: &HashMap<Method, H>) -> Vec<Method> {
+    let mut methods: Vec<Method> = handlers.keys().cloned().collect();
+    if handlers.contains_key(&Method::GET) && !handlers.contains_key(&Method::HEAD) {
+        methods.push(Method::HEAD);
+    }
+    if !handlers.contains_key(&Method::OPTIONS) {
+        methods.push(Method::OPTIONS);
+    }
+    methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    methods
+}
+
+// Split a path into segments, ignoring the leading slash
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::Next;
+    use crate::response::Body;
+    use http::StatusCode;
+
+    fn ok(_: &Request) -> Response {
+        Response::new(StatusCode::OK)
+    }
+
+    struct Tag(&'static str);
+
+    impl Middleware for Tag {
+        fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+            next.run(request)
+        }
+
+        fn name(&self) -> &'static str {
+            self.0
+        }
+    }
+
+    fn middleware_of(router: &Router, method: Method, path: &str) -> Vec<&'static str> {
+        match router.find(&method, path) {
+            RouteMatch::Found { middleware, .. }
+            | RouteMatch::MethodNotAllowed { middleware, .. }
+            | RouteMatch::Options { middleware, .. } => middleware
+                .iter()
+                .map(|middleware| middleware.name())
+                .collect(),
+            RouteMatch::NotFound => panic!("no route for {} {}", method, path),
+        }
+    }
+
+    #[test]
+    fn prefers_literals_then_params_then_catch_alls() {
+        let mut router = Router::new();
+        router.get("/users/me", ok);
+        router.get("/users/:id", ok);
+        router.get("/users/:id/files/*path", ok);
+
+        match router.find(&Method::GET, "/users/42/files/a/b%2Fc") {
+            RouteMatch::Found { params, .. } => {
+                assert_eq!(params.get("id"), Some("42"));
+                assert_eq!(params.get("path"), Some("a/b/c"));
+            }
+            _ => panic!("expected a match"),
+        }
+        match router.find(&Method::GET, "/users/me") {
+            RouteMatch::Found { params, .. } => assert!(params.is_empty()),
+            _ => panic!("expected a match"),
+        }
+        assert!(matches!(
+            router.find(&Method::GET, "/users"),
+            RouteMatch::NotFound
+        ));
+    }
+
+    #[test]
+    fn group_middleware_only_wraps_the_group() {
+        let mut router = Router::new();
+        router.middleware(Tag("Log"));
+        router.prefix_middleware("/api", Tag("Cors"));
+        router.get("/api/health", ok);
+        router.group("/api", |api| {
+            api.middleware(Tag("Auth"));
+            api.prefix_middleware("/admin", Tag("Admin"));
+            api.get("/private", ok);
+            api.get("/admin/users", ok);
+        });
+        router.group("/api", |api| api.get("/public", ok));
+
+        assert_eq!(middleware_of(&router, Method::GET, "/api/health"), ["Cors"]);
+        assert_eq!(middleware_of(&router, Method::GET, "/api/public"), ["Cors"]);
+        assert_eq!(
+            middleware_of(&router, Method::GET, "/api/private"),
+            ["Cors", "Auth"]
+        );
+        assert_eq!(
+            middleware_of(&router, Method::GET, "/api/admin/users"),
+            ["Cors", "Auth", "Admin"]
+        );
+
+        let routes = router.routes();
+        let route = |pattern: &str| {
+            let route = routes
+                .iter()
+                .find(|route| route.pattern == pattern)
+                .unwrap();
+            route.middleware.clone()
+        };
+        assert_eq!(route("/api/public"), ["Log", "Cors"]);
+        assert_eq!(route("/api/admin/users"), ["Log", "Cors", "Auth", "Admin"]);
+    }
+
+    #[test]
+    fn nested_groups_run_outer_middleware_first() {
+        let mut router = Router::new();
+        router.group("/a", |a| {
+            a.middleware(Tag("Outer"));
+            a.group("/b", |b| {
+                b.middleware(Tag("Inner"));
+                b.get("/c", ok);
+            });
+        });
+
+        assert_eq!(
+            middleware_of(&router, Method::GET, "/a/b/c"),
+            ["Outer", "Inner"]
+        );
+    }
+
+    #[test]
+    fn preflights_and_405s_carry_route_middleware() {
+        let mut router = Router::new();
+        router.prefix_middleware("/api", Tag("Cors"));
+        router.group("/api", |api| {
+            api.middleware(Tag("Auth"));
+            api.get("/items", ok);
+        });
+
+        match router.find(&Method::OPTIONS, "/api/items") {
+            RouteMatch::Options { allowed, .. } => {
+                assert_eq!(allowed, [Method::GET, Method::HEAD, Method::OPTIONS])
+            }
+            _ => panic!("expected an automatic OPTIONS reply"),
+        }
+        assert_eq!(
+            middleware_of(&router, Method::OPTIONS, "/api/items"),
+            ["Cors", "Auth"]
+        );
+        assert!(matches!(
+            router.find(&Method::DELETE, "/api/items"),
+            RouteMatch::MethodNotAllowed { .. }
+        ));
+        assert_eq!(
+            middleware_of(&router, Method::DELETE, "/api/items"),
+            ["Cors", "Auth"]
+        );
+    }
+
+    #[test]
+    fn mounting_keeps_the_parents_state() {
+        let mut api = Router::new();
+        api.state(2u32);
+        api.state("api");
+        let mut router = Router::new();
+        router.state(1u32);
+        router.mount("/api", api);
+
+        assert_eq!(router.app_state().get::<u32>(), Some(&1));
+        assert_eq!(router.app_state().get::<&str>(), Some(&"api"));
+    }
+
+    #[test]
+    fn closures_capture_values_and_read_state() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let greeting = String::from("hello");
+        let mut router = Router::new();
+        router.state(String::from("world"));
+        router.get("/greet", move |request: &Request| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let name = request
+                .state::<String>()
+                .map_or("nobody", |name| name.as_str());
+            Response::text(StatusCode::OK, format!("{}, {}", greeting, name))
+        });
+
+        for _ in 0..2 {
+            let mut request = Request::for_test("GET", "/greet", &[], b"");
+            request.app_state = router.app_state().clone();
+            let response = match router.find(&Method::GET, "/greet") {
+                RouteMatch::Found { handler, .. } => handler.call(&request),
+                _ => panic!("expected a match"),
+            };
+            assert!(matches!(response.body, Body::Bytes(ref body) if body == b"hello, world"));
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+}
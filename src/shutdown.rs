@@ -0,0 +1,371 @@
+use crate::connection::{Listener, Stream};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::Shutdown;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// How often draining looks for connections that turned idle and can be closed
+const DRAIN_IDLE_CHECK: Duration = Duration::from_millis(100);
+
+// Set once SIGINT/SIGTERM arrives or shutdown is requested programmatically
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Ends of a pipe written to on shutdown, so the accept loop can sleep until a
+// connection or a shutdown request arrives; -1 until the signal handlers are installed
+#[cfg(unix)]
+static WAKE_READ_FD: AtomicI32 = AtomicI32::new(-1);
+#[cfg(unix)]
+static WAKE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(unix)]
+extern "C" {
+    fn pipe(fds: *mut i32) -> i32;
+    fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+}
+
+// Whether the server has been asked to stop
+pub fn is_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+// Ask the server to stop accepting connections and drain
+pub fn request() {
+    if !SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        wake();
+    }
+}
+
+// Signal-safe: only an atomic load and a write(2)
+#[cfg(unix)]
+fn wake() {
+    let fd = WAKE_WRITE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // SAFETY: writes one byte from a live buffer; a failed write only loses the wakeup
+        unsafe { write(fd, [1u8].as_ptr(), 1) };
+    }
+}
+
+#[cfg(not(unix))]
+fn wake() {}
+
+// Block until one of the listeners has a connection waiting or shutdown is requested
+//
+// Returns early, without error, when a signal interrupts the wait. Shutdown
+// only wakes the wait once `install_signal_handlers` has set up the pipe.
+#[cfg(unix)]
+pub fn wait_for_connection(listeners: &[Listener]) -> io::Result<()> {
+    #[repr(C)]
+    struct PollFd {
+        fd: i32,
+        events: i16,
+        revents: i16,
+    }
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    type NfdsT = std::os::raw::c_uint;
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    type NfdsT = std::os::raw::c_ulong;
+    const POLLIN: i16 = 1;
+
+    extern "C" {
+        fn poll(fds: *mut PollFd, nfds: NfdsT, timeout: i32) -> i32;
+    }
+
+    let wake = Some(WAKE_READ_FD.load(Ordering::SeqCst)).filter(|fd| *fd >= 0);
+    let mut fds: Vec<PollFd> = listeners
+        .iter()
+        .map(AsRawFd::as_raw_fd)
+        .chain(wake)
+        .map(|fd| PollFd {
+            fd,
+            events: POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // SAFETY: `fds` is a live array of `fds.len()` pollfd structures
+    if unsafe { poll(fds.as_mut_ptr(), fds.len() as NfdsT, -1) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+// Without poll(2) the listeners are checked at a short interval instead
+#[cfg(not(unix))]
+pub fn wait_for_connection(_: &[Listener]) -> io::Result<()> {
+    std::thread::sleep(Duration::from_millis(50));
+    Ok(())
+}
+
+// Install SIGINT and SIGTERM handlers that trigger a graceful shutdown
+//
+// A second signal while draining exits immediately.
+#[cfg(unix)]
+pub fn install_signal_handlers() -> io::Result<()> {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    const SIG_ERR: usize = usize::MAX;
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    // Only async-signal-safe operations are allowed in here
+    extern "C" fn on_signal(_: i32) {
+        if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+            unsafe { _exit(1) };
+        }
+        wake();
+    }
+
+    if WAKE_READ_FD.load(Ordering::SeqCst) < 0 {
+        let mut fds = [-1; 2];
+        // SAFETY: pipe fills in exactly two descriptors
+        if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        WAKE_READ_FD.store(fds[0], Ordering::SeqCst);
+        WAKE_WRITE_FD.store(fds[1], Ordering::SeqCst);
+    }
+
+    for signum in [SIGINT, SIGTERM] {
+        // SAFETY: the handler only touches an atomic and calls _exit, both signal-safe
+        if unsafe { signal(signum, on_signal) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() -> io::Result<()> {
+    Ok(())
+}
+
+// Registry of open connections so shutdown can wait for them or cut them off
+#[derive(Default)]
+pub struct ConnectionTracker {
+    active: Mutex<HashMap<u64, Tracked>>,
+    drained: Condvar,
+    next_id: AtomicU64,
+}
+
+struct Tracked {
+    stream: Stream,
+    // Set while the connection waits for another keep-alive request
+    idle: Arc<AtomicBool>,
+}
+
+// Removes its connection from the tracker when dropped
+pub struct ConnectionGuard {
+    tracker: Arc<ConnectionTracker>,
+    id: u64,
+    idle: Arc<AtomicBool>,
+}
+
+impl ConnectionGuard {
+    // Mark the connection as waiting between requests, which lets
+    // `ConnectionTracker::close_idle` close it to free its worker
+    pub fn set_idle(&self, idle: bool) {
+        self.idle.store(idle, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut active = self
+            .tracker
+            .active
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        active.remove(&self.id);
+        self.tracker.drained.notify_all();
+    }
+}
+
+// Outcome of draining connections at shutdown
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DrainReport {
+    pub drained: usize,
+    pub aborted: usize,
+}
+
+impl ConnectionTracker {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ConnectionTracker::default())
+    }
+
+    // Track a connection until the returned guard is dropped
+    pub fn register(self: &Arc<Self>, stream: &Stream) -> io::Result<ConnectionGuard> {
+        let handle = stream.try_clone()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let idle = Arc::new(AtomicBool::new(false));
+        self.lock().insert(
+            id,
+            Tracked {
+                stream: handle,
+                idle: idle.clone(),
+            },
+        );
+
+        Ok(ConnectionGuard {
+            tracker: self.clone(),
+            id,
+            idle,
+        })
+    }
+
+    // Number of connections currently open
+    pub fn active_count(&self) -> usize {
+        self.lock().len()
+    }
+
+    // Close the connections waiting for another keep-alive request, returning how many
+    //
+    // Their workers see the connection end and move on to queued connections.
+    // A request the client sends at that moment is lost, which clients already
+    // expect of keep-alive connections and retry if it was idempotent.
+    pub fn close_idle(&self) -> usize {
+        close_idle(&self.lock())
+    }
+
+    // Wait for open connections to finish, closing any still open after the deadline
+    //
+    // Idle keep-alive connections have nothing left to finish, so they are
+    // closed right away, and so is any connection that turns idle meanwhile.
+    pub fn drain(&self, deadline: Duration) -> DrainReport {
+        let started = Instant::now();
+        let mut active = self.lock();
+        let initial = active.len();
+
+        if initial > 0 {
+            info!("Waiting up to {:?} for {} connections", deadline, initial);
+        }
+
+        while !active.is_empty() {
+            close_idle(&active);
+            let remaining = match deadline.checked_sub(started.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => break,
+            };
+            active = self
+                .drained
+                .wait_timeout(active, remaining.min(DRAIN_IDLE_CHECK))
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+
+        let aborted = active.len();
+        for tracked in active.values() {
+            if let Err(err) = tracked.stream.shutdown(Shutdown::Both) {
+                warn!("Failed to close connection: {}", err);
+            }
+        }
+
+        DrainReport {
+            drained: initial - aborted,
+            aborted,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Tracked>> {
+        self.active.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+// Shut down the idle connections among `active`, returning how many
+fn close_idle(active: &HashMap<u64, Tracked>) -> usize {
+    let mut closed = 0;
+    for tracked in active.values() {
+        if tracked.idle.swap(false, Ordering::SeqCst) {
+            if let Err(err) = tracked.stream.shutdown(Shutdown::Both) {
+                warn!("Failed to close idle connection: {}", err);
+            }
+            closed += 1;
+        }
+    }
+    closed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ListenAddr;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    fn connection(listener: &TcpListener) -> (Stream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = Stream::Tcp(listener.accept().unwrap().0);
+        (server, client)
+    }
+
+    #[test]
+    fn closes_only_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker = ConnectionTracker::new();
+        let (busy, mut busy_client) = connection(&listener);
+        let (idle, mut idle_client) = connection(&listener);
+        let busy_guard = tracker.register(&busy).unwrap();
+        let idle_guard = tracker.register(&idle).unwrap();
+
+        idle_guard.set_idle(true);
+        assert_eq!(tracker.close_idle(), 1);
+        assert_eq!(tracker.close_idle(), 0);
+        assert_eq!(idle_client.read(&mut [0; 1]).unwrap(), 0);
+
+        busy_client
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        assert!(busy_client.read(&mut [0; 1]).is_err());
+
+        drop(idle_guard);
+        assert_eq!(tracker.active_count(), 1);
+        drop(busy_guard);
+        assert_eq!(tracker.active_count(), 0);
+    }
+
+    #[test]
+    fn drain_closes_idle_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tracker = ConnectionTracker::new();
+        let (mut server, _client) = connection(&listener);
+        let guard = tracker.register(&server).unwrap();
+
+        // Stands in for a worker waiting for the next keep-alive request
+        let worker = std::thread::spawn(move || {
+            guard.set_idle(true);
+            while server.read(&mut [0; 64]).unwrap_or(0) > 0 {}
+            drop(guard);
+        });
+
+        let report = tracker.drain(Duration::from_secs(2));
+        assert_eq!((report.drained, report.aborted), (1, 0));
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn wakes_when_a_connection_is_waiting() {
+        let listener = Listener::bind(&ListenAddr::Tcp("127.0.0.1:0".parse().unwrap())).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = match listener.local_addr().unwrap() {
+            ListenAddr::Tcp(address) => address,
+            #[cfg(unix)]
+            other => panic!("unexpected address {}", other),
+        };
+
+        let _client = TcpStream::connect(address).unwrap();
+        wait_for_connection(std::slice::from_ref(&listener)).unwrap();
+        assert!(listener.accept().is_ok());
+    }
+}
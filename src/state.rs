@@ -0,0 +1,93 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Shared application state, holding at most one value of each type
+//
+// Registered on the router at startup and handed to every handler through
+// `Request::state`. Values are shared between worker threads, so anything
+// mutable needs its own synchronisation, such as an atomic or a Mutex.
+#[derive(Clone, Default)]
+pub struct AppState {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        AppState::default()
+    }
+
+    // Store a value, replacing any earlier value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.values.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // Add the values of another container for types this one does not hold yet
+    pub fn extend_missing(&mut self, other: &AppState) {
+        for (id, value) in &other.values {
+            self.values.entry(*id).or_insert_with(|| Arc::clone(value));
+        }
+    }
+}
+
+impl fmt::Debug for AppState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppState")
+            .field("values", &self.values.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_one_value_per_type() {
+        let mut state = AppState::new();
+        assert!(state.is_empty());
+        state.insert(1u32);
+        state.insert(String::from("config"));
+        state.insert(2u32);
+
+        assert_eq!(state.len(), 2);
+        assert_eq!(state.get::<u32>(), Some(&2));
+        assert_eq!(state.get::<String>().map(String::as_str), Some("config"));
+        assert!(state.contains::<u32>());
+        assert!(!state.contains::<u64>());
+        assert_eq!(state.get::<u64>(), None);
+    }
+
+    #[test]
+    fn extends_only_missing_types() {
+        let mut state = AppState::new();
+        state.insert(1u32);
+        let mut other = AppState::new();
+        other.insert(2u32);
+        other.insert(true);
+
+        state.extend_missing(&other);
+        assert_eq!(state.len(), 2);
+        assert_eq!(state.get::<u32>(), Some(&1));
+        assert_eq!(state.get::<bool>(), Some(&true));
+    }
+}
@@ -0,0 +1,429 @@
+use crate::conditional::file_etag;
+use crate::httpdate;
+use crate::request::Request;
+use crate::response::{Body, Response};
+use crate::url::percent_encode_segment;
+use http::{header, StatusCode};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+// HTML template for generated directory listings
+const LISTING_TEMPLATE: &str = r#"
+<!DOCTYPE html>
+<html>
+<head>
+    <title>{title}</title>
+</head>
+<body>
+    <h1>{title}</h1>
+    <ul>
+{content}    </ul>
+</body>
+</html>
+"#;
+
+// Serves files from a directory on disk for requests under a route prefix
+//
+// Mount it on a catch-all route such as `/static/*path`; the captured
+// parameter is resolved against the root directory.
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: PathBuf,
+    param: String,
+    index_file: Option<String>,
+    directory_listing: bool,
+}
+
+impl StaticFiles {
+    // Serve files below `root`, which must exist
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+
+        Ok(StaticFiles {
+            root,
+            param: "path".to_string(),
+            index_file: Some("index.html".to_string()),
+            directory_listing: false,
+        })
+    }
+
+    // Name of the catch-all route parameter holding the file path (default `path`)
+    pub fn param(mut self, name: &str) -> Self {
+        self.param = name.to_string();
+        self
+    }
+
+    // File served for directory requests, or None to disable (default `index.html`)
+    pub fn index_file(mut self, name: Option<&str>) -> Self {
+        self.index_file = name.map(str::to_string);
+        self
+    }
+
+    // Generate a listing for directories without an index file (default off)
+    pub fn directory_listing(mut self, enabled: bool) -> Self {
+        self.directory_listing = enabled;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // Resolve the request to a file and serve it, or return the error status to render
+    pub fn serve(&self, request: &Request) -> Result<Response, StatusCode> {
+        // Encoded separators would let a single segment smuggle a path; refuse them outright
+        let raw_path = request.path.to_ascii_lowercase();
+        if raw_path.contains("%2f") || raw_path.contains("%5c") || raw_path.contains("%00") {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let relative = request.param(&self.param).unwrap_or("");
+        let path = self.resolve(relative)?;
+
+        let metadata = fs::metadata(&path).map_err(|_| StatusCode::NOT_FOUND)?;
+        if metadata.is_dir() {
+            let at_root = relative.trim_matches('/').is_empty();
+            return self.serve_directory(request, &path, at_root);
+        }
+
+        serve_file(&path)
+    }
+
+    // Map a relative URL path onto the root, refusing anything that escapes it
+    fn resolve(&self, relative: &str) -> Result<PathBuf, StatusCode> {
+        let mut path = self.root.clone();
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            if segment.contains('\\') || segment.contains('\0') {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            let mut components = Path::new(segment).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(part)), None) => path.push(part),
+                _ => return Err(StatusCode::FORBIDDEN),
+            }
+        }
+
+        // Canonicalizing resolves symlinks, so a link pointing outside the root is caught here
+        let resolved = fs::canonicalize(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            _ => StatusCode::NOT_FOUND,
+        })?;
+        if !resolved.starts_with(&self.root) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        Ok(resolved)
+    }
+
+    fn serve_directory(
+        &self,
+        request: &Request,
+        path: &Path,
+        at_root: bool,
+    ) -> Result<Response, StatusCode> {
+        // Relative links inside the page only work when the URL ends with a slash
+        if !request.path.ends_with('/') {
+            let mut location = format!("{}/", request.path);
+            if let Some(query) = &request.query {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Ok(
+                Response::new(StatusCode::MOVED_PERMANENTLY).header(header::LOCATION, location)
+            );
+        }
+
+        if let Some(index_file) = &self.index_file {
+            let index = path.join(index_file);
+            if index.is_file() {
+                return serve_file(&index);
+            }
+        }
+
+        if self.directory_listing {
+            return render_listing(&request.path, path, at_root);
+        }
+
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+fn serve_file(path: &Path) -> Result<Response, StatusCode> {
+    let file = File::open(path).map_err(|err| match err.kind() {
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::NOT_FOUND,
+    })?;
+    let metadata = file
+        .metadata()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let length = metadata.len();
+
+    let mut response = Response::new(StatusCode::OK).header(header::CONTENT_TYPE, mime_type(path));
+    if let Ok(modified) = metadata.modified() {
+        response = response
+            .header(header::ETAG, file_etag(length, modified))
+            .header(header::LAST_MODIFIED, httpdate::format(modified));
+    }
+
+    Ok(response.body(Body::File { file, length }))
+}
+
+// `at_root` leaves out the parent link, which would lead outside the mount point
+fn render_listing(url_path: &str, path: &Path, at_root: bool) -> Result<Response, StatusCode> {
+    let mut entries: Vec<(String, bool)> = fs::read_dir(path)
+        .map_err(|_| StatusCode::FORBIDDEN)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let is_dir = entry.file_type().ok()?.is_dir();
+            Some((name, is_dir))
+        })
+        .filter(|(name, _)| !name.starts_with('.'))
+        .collect();
+    entries.sort();
+
+    let mut content = String::new();
+    if !at_root {
+        content.push_str("        <li><a href=\"../\">../</a></li>\n");
+    }
+    for (name, is_dir) in entries {
+        let suffix = if is_dir { "/" } else { "" };
+        content.push_str(&format!(
+            "        <li><a href=\"{}{}\">{}{}</a></li>\n",
+            percent_encode_segment(&name),
+            suffix,
+            escape_html(&name),
+            suffix
+        ));
+    }
+
+    let title = format!("Index of {}", escape_html(url_path));
+    let page = LISTING_TEMPLATE
+        .replace("{title}", &title)
+        .replace("{content}", &content);
+
+    Ok(Response::html(StatusCode::OK, page))
+}
+
+// Escape text for safe inclusion in HTML
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Guess a MIME type from the file extension
+pub fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{RouteMatch, Router};
+
+    // A served directory next to a secret file that a symlink inside it points at
+    //
+    // root/a.txt, root/site/index.html, root/sub/b.txt, root/escape -> outside/
+    fn fixture(name: &str) -> PathBuf {
+        let base =
+            std::env::temp_dir().join(format!("static-files-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&base);
+        let root = base.join("root");
+        fs::create_dir_all(root.join("site")).unwrap();
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::create_dir_all(base.join("outside")).unwrap();
+        fs::write(root.join("a.txt"), "hello").unwrap();
+        fs::write(root.join("site/index.html"), "<p>index</p>").unwrap();
+        fs::write(root.join("sub/b.txt"), "b").unwrap();
+        fs::write(base.join("outside/secret.txt"), "secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(base.join("outside"), root.join("escape")).unwrap();
+        base
+    }
+
+    // Route `target` through `/static/*path` and serve it
+    fn get(files: &StaticFiles, target: &str) -> Result<Response, StatusCode> {
+        let mut request = Request::for_test("GET", target, &[], b"");
+
+        let mut router = Router::new();
+        router.get("/static/*path", |_: &Request| Response::new(StatusCode::OK));
+        match router.find(&request.method, &request.path) {
+            RouteMatch::Found { params, .. } => request.params = params,
+            _ => panic!("{} does not match the static route", target),
+        }
+        files.serve(&request)
+    }
+
+    fn text(response: Response) -> String {
+        match response.body {
+            Body::Bytes(bytes) => String::from_utf8(bytes).unwrap(),
+            Body::File { mut file, .. } => io::read_to_string(&mut file).unwrap(),
+            _ => panic!("unexpected body"),
+        }
+    }
+
+    #[test]
+    fn serves_files_below_the_root() {
+        let base = fixture("files");
+        let files = StaticFiles::new(base.join("root")).unwrap();
+
+        let response = get(&files, "/static/a.txt").unwrap();
+        assert_eq!(
+            response.headers[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        assert!(response.headers.contains_key(header::ETAG));
+        assert_eq!(text(response), "hello");
+        assert_eq!(
+            get(&files, "/static/missing.txt").unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn refuses_paths_that_escape_the_root() {
+        let base = fixture("escape");
+        let files = StaticFiles::new(base.join("root")).unwrap();
+
+        assert_eq!(
+            files.resolve("../outside/secret.txt"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(
+            files.resolve("sub/../../outside"),
+            Err(StatusCode::FORBIDDEN)
+        );
+        for target in [
+            "/static/../outside/secret.txt",
+            "/static/..%2foutside%2fsecret.txt",
+            "/static/..%5coutside%5csecret.txt",
+            "/static/a.txt%00.html",
+            "/static/sub%2F..%2F..%2Foutside",
+        ] {
+            assert_eq!(
+                get(&files, target).unwrap_err(),
+                StatusCode::FORBIDDEN,
+                "{}",
+                target
+            );
+        }
+
+        #[cfg(unix)]
+        assert_eq!(
+            get(&files, "/static/escape/secret.txt").unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn redirects_directories_to_a_trailing_slash() {
+        let base = fixture("redirect");
+        let files = StaticFiles::new(base.join("root")).unwrap();
+
+        let response = get(&files, "/static/site?v=1").unwrap();
+        assert_eq!(response.status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers[header::LOCATION], "/static/site/?v=1");
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn serves_the_index_file_of_a_directory() {
+        let base = fixture("index");
+        let files = StaticFiles::new(base.join("root")).unwrap();
+
+        let response = get(&files, "/static/site/").unwrap();
+        assert_eq!(
+            response.headers[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(text(response), "<p>index</p>");
+
+        let files = files.index_file(None);
+        assert_eq!(
+            get(&files, "/static/site/").unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn lists_directories_only_when_enabled() {
+        let base = fixture("listing");
+        let files = StaticFiles::new(base.join("root")).unwrap();
+        assert_eq!(
+            get(&files, "/static/sub/").unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+
+        let files = files.directory_listing(true);
+        let listing = text(get(&files, "/static/sub/").unwrap());
+        assert!(listing.contains("Index of /static/sub/"), "{}", listing);
+        assert!(
+            listing.contains("<a href=\"b.txt\">b.txt</a>"),
+            "{}",
+            listing
+        );
+        assert!(listing.contains("<a href=\"../\">"), "{}", listing);
+
+        // The mount root has no parent to link to
+        let listing = text(get(&files, "/static/").unwrap());
+        assert!(listing.contains("<a href=\"sub/\">sub/</a>"), "{}", listing);
+        assert!(!listing.contains("../"), "{}", listing);
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+use std::error::Error;
+use std::fmt;
+
+// A value from the TOML subset understood by the config loader
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(value) => write!(f, "{:?}", value),
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Boolean(value) => write!(f, "{}", value),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+// Syntax error with the 1-based line it occurred on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+// Parse a TOML document into `(dotted.key, value)` pairs in document order
+//
+// Supports `[table]` headers, bare and quoted keys, basic strings, integers,
+// booleans, single-line arrays and `#` comments. That covers the config file
+// format without pulling in a full TOML implementation.
+pub fn parse(input: &str) -> Result<Vec<(String, Value)>, ParseError> {
+    let mut entries: Vec<(String, Value)> = Vec::new();
+    let mut table = String::new();
+
+    for (index, raw_line) in input.lines().enumerate() {
+        let line_number = index + 1;
+        let error = |message: &str| ParseError {
+            line: line_number,
+            message: message.to_string(),
+        };
+
+        let mut cursor = Cursor::new(raw_line);
+        cursor.skip_whitespace();
+        if cursor.at_end_of_line() {
+            continue;
+        }
+
+        if cursor.eat('[') {
+            cursor.skip_whitespace();
+            table = cursor.key().map_err(|message| error(&message))?;
+            cursor.skip_whitespace();
+            if !cursor.eat(']') {
+                return Err(error("expected ']' after table name"));
+            }
+            cursor.skip_whitespace();
+            if !cursor.at_end_of_line() {
+                return Err(error("unexpected characters after table header"));
+            }
+            continue;
+        }
+
+        let key = cursor.key().map_err(|message| error(&message))?;
+        cursor.skip_whitespace();
+        if !cursor.eat('=') {
+            return Err(error("expected '=' after key"));
+        }
+        cursor.skip_whitespace();
+        let value = cursor.value().map_err(|message| error(&message))?;
+        cursor.skip_whitespace();
+        if !cursor.at_end_of_line() {
+            return Err(error("unexpected characters after value"));
+        }
+
+        let key = if table.is_empty() {
+            key
+        } else {
+            format!("{}.{}", table, key)
+        };
+        if entries.iter().any(|(existing, _)| *existing == key) {
+            return Err(error(&format!("duplicate key {}", key)));
+        }
+        entries.push((key, value));
+    }
+
+    Ok(entries)
+}
+
+struct Cursor<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(line: &'a str) -> Self {
+        Cursor {
+            chars: line.chars().peekable(),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.chars.peek(), Some(' ') | Some('\t')) {
+            self.chars.next();
+        }
+    }
+
+    fn at_end_of_line(&mut self) -> bool {
+        matches!(self.chars.peek(), None | Some('#'))
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.chars.peek() == Some(&expected) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    // A dotted key made of bare or quoted parts
+    fn key(&mut self) -> Result<String, String> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let part = if self.eat('"') {
+                self.string_body()?
+            } else {
+                let mut part = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        part.push(c);
+                        self.chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if part.is_empty() {
+                    return Err("expected a key".to_string());
+                }
+                part
+            };
+            parts.push(part);
+            self.skip_whitespace();
+            if !self.eat('.') {
+                return Ok(parts.join("."));
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        match self.chars.peek() {
+            Some('"') => {
+                self.chars.next();
+                self.string_body().map(Value::String)
+            }
+            Some('[') => {
+                self.chars.next();
+                self.array()
+            }
+            Some(_) => {
+                let mut token = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+' {
+                        token.push(c);
+                        self.chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                match token.as_str() {
+                    "true" => Ok(Value::Boolean(true)),
+                    "false" => Ok(Value::Boolean(false)),
+                    _ => token
+                        .replace('_', "")
+                        .parse::<i64>()
+                        .map(Value::Integer)
+                        .map_err(|_| format!("invalid value {:?}", token)),
+                }
+            }
+            None => Err("expected a value".to_string()),
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(Value::Array(values));
+            }
+            if !self.eat(',') {
+                return Err("expected ',' or ']' in array".to_string());
+            }
+        }
+    }
+
+    // The rest of a basic string after its opening quote
+    fn string_body(&mut self) -> Result<String, String> {
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(value),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    _ => return Err("invalid escape sequence in string".to_string()),
+                },
+                Some(c) => value.push(c),
+                None => return Err("unterminated string".to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_line(input: &str) -> usize {
+        parse(input).unwrap_err().line
+    }
+
+    #[test]
+    fn parses_tables_and_values() {
+        let input = r#"
+# server settings
+workers = 4
+listen = ["127.0.0.1:80", "unix:/run/app.sock"] # trailing comment
+
+[keep_alive]
+"idle.timeout" = "5s"
+enabled = true
+max_body = 1_048_576
+"#;
+        assert_eq!(
+            parse(input).unwrap(),
+            [
+                ("workers".to_string(), Value::Integer(4)),
+                (
+                    "listen".to_string(),
+                    Value::Array(vec![
+                        Value::String("127.0.0.1:80".to_string()),
+                        Value::String("unix:/run/app.sock".to_string()),
+                    ])
+                ),
+                (
+                    "keep_alive.idle.timeout".to_string(),
+                    Value::String("5s".to_string())
+                ),
+                ("keep_alive.enabled".to_string(), Value::Boolean(true)),
+                ("keep_alive.max_body".to_string(), Value::Integer(1_048_576)),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_string_escapes() {
+        let entries = parse(r#"a = "tab\tquote\"slash\\""#).unwrap();
+        assert_eq!(
+            entries[0].1,
+            Value::String("tab\tquote\"slash\\".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        assert_eq!(error_line("a = 1\nb 2"), 2);
+        assert_eq!(error_line("a = \"open"), 1);
+        assert_eq!(error_line("a = \"\\x\""), 1);
+        assert_eq!(error_line("a = 1\n\n[t\n"), 3);
+        assert_eq!(error_line("a = [1, 2"), 1);
+        assert_eq!(error_line("a = 1 2"), 1);
+        assert_eq!(error_line("a = 99999999999999999999"), 1);
+        assert_eq!(error_line("a = 1\n[t]\nb = 1\n[t]\nb = 2"), 5);
+    }
+}
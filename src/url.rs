@@ -0,0 +1,199 @@
+use std::error::Error;
+use std::fmt;
+
+// Error returned for malformed percent-encoding or non-UTF-8 decoded output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError;
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed percent-encoding")
+    }
+}
+
+impl Error for DecodeError {}
+
+// Decode `%XX` escapes, optionally treating `+` as a space as in query strings and forms
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Result<String, DecodeError> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'%' => {
+                let high = bytes.get(index + 1).and_then(|byte| hex_value(*byte));
+                let low = bytes.get(index + 2).and_then(|byte| hex_value(*byte));
+                match (high, low) {
+                    (Some(high), Some(low)) => decoded.push(high << 4 | low),
+                    _ => return Err(DecodeError),
+                }
+                index += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                index += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| DecodeError)
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Ordered multi-map of decoded `key=value` pairs from a query string
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryParams {
+    entries: Vec<(String, String)>,
+}
+
+impl QueryParams {
+    // Parse an `application/x-www-form-urlencoded` string such as `a=1&b=2&a=3`
+    pub fn parse(input: &str) -> Result<Self, DecodeError> {
+        let mut entries = Vec::new();
+
+        for pair in input.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            entries.push((percent_decode(key, true)?, percent_decode(value, true)?));
+        }
+
+        Ok(QueryParams { entries })
+    }
+
+    // First value for a key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // All values for a key, in the order they appeared
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.entries.iter().any(|(name, _)| name == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl FromIterator<(String, String)> for QueryParams {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(iter: I) -> Self {
+        QueryParams {
+            entries: iter.into_iter().collect(),
+        }
+    }
+}
+
+// Percent-encode a single path segment, leaving only unreserved characters as-is
+pub fn percent_encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Request;
+    use http::StatusCode;
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc", false).unwrap(), "a b/c");
+        assert_eq!(percent_decode("caf%C3%A9", false).unwrap(), "café");
+        assert_eq!(percent_decode("a+b", false).unwrap(), "a+b");
+        assert_eq!(percent_decode("a+b", true).unwrap(), "a b");
+
+        assert_eq!(percent_decode("100%", false), Err(DecodeError));
+        assert_eq!(percent_decode("%2", false), Err(DecodeError));
+        assert_eq!(percent_decode("%zz", false), Err(DecodeError));
+        // Escapes must decode to UTF-8
+        assert_eq!(percent_decode("%FF", false), Err(DecodeError));
+    }
+
+    #[test]
+    fn keeps_repeated_query_keys_in_order() {
+        let params = QueryParams::parse("tag=a&name=J%C3%B6rg+M&tag=b&&flag&empty=").unwrap();
+        assert_eq!(params.len(), 5);
+        assert_eq!(params.get("tag"), Some("a"));
+        assert_eq!(params.get_all("tag").collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(params.get("name"), Some("Jörg M"));
+        assert_eq!(params.get("flag"), Some(""));
+        assert_eq!(params.get("empty"), Some(""));
+        assert!(params.contains_key("flag"));
+        assert_eq!(params.get("missing"), None);
+
+        assert!(QueryParams::parse("").unwrap().is_empty());
+        assert_eq!(QueryParams::parse("a=%ZZ"), Err(DecodeError));
+    }
+
+    #[test]
+    fn encodes_path_segments() {
+        assert_eq!(percent_encode_segment("a b/c?.txt"), "a%20b%2Fc%3F.txt");
+        assert_eq!(percent_encode_segment("Ünï"), "%C3%9Cn%C3%AF");
+        assert_eq!(percent_encode_segment("safe-_.~09"), "safe-_.~09");
+    }
+
+    #[test]
+    fn rejects_requests_with_bad_encoding() {
+        for target in ["/files/%zz", "/search?q=%E2%82", "/a%"] {
+            let head = format!("GET {} HTTP/1.1\r\n\r\n", target);
+            let mut headers = [httparse::EMPTY_HEADER; 1];
+            let mut parsed = httparse::Request::new(&mut headers);
+            parsed.parse(head.as_bytes()).unwrap();
+            let err = Request::from_parsed(&parsed, Vec::new()).unwrap_err();
+            assert_eq!(
+                err.status_code(),
+                Some(StatusCode::BAD_REQUEST),
+                "{}",
+                target
+            );
+        }
+
+        let request = Request::for_test("GET", "/a%20b?x=1+2&x=%26", &[], b"");
+        assert_eq!(request.path, "/a%20b");
+        assert_eq!(request.query.as_deref(), Some("x=1+2&x=%26"));
+        assert_eq!(
+            request.query_params.get_all("x").collect::<Vec<_>>(),
+            ["1 2", "&"]
+        );
+    }
+}